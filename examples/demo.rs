@@ -1,6 +1,6 @@
 use log::*;
 
-use comacro::PatternDef;
+use comacro::ast::{Enclosing, Input, Pattern, PatternDef};
 
 fn main() {
     env_logger::init();
@@ -36,11 +36,15 @@ fn main() {
         use syn::Item::*;
         match it {
             Fn(f) => {
-                let input = &f.block.stmts;
-                let in_trace = comacro::compile_input(input);
-                for (i, pat) in pats.iter().enumerate() {
-                    for m in pat.matches(input, &in_trace) {
-                        println!("pattern {} is a match: {:?}", i, m);
+                let items = [Enclosing::of_fn(&f)];
+                find(&pats, f.block.stmts, &items);
+            }
+            Impl(i) => {
+                let imp = Enclosing::of_impl(&i);
+                for it in i.items {
+                    if let syn::ImplItem::Method(m) = it {
+                        let items = [imp.clone(), Enclosing::of_method(&m)];
+                        find(&pats, m.block.stmts, &items);
                     }
                 }
             }
@@ -49,13 +53,23 @@ fn main() {
     }
 }
 
-fn compile_pat(item: &syn::Item) -> comacro::Ir {
+fn find(pats: &[Pattern], stmts: Vec<syn::Stmt>, items: &[Enclosing]) {
+    let input = Input { stmts };
+    let in_trace = input.compile();
+    for (i, pat) in pats.iter().enumerate() {
+        for m in pat.matches(&input.stmts, &in_trace).within(items) {
+            println!("pattern {} is a match: {:?}", i, m);
+        }
+    }
+}
+
+fn compile_pat(item: &syn::Item) -> Pattern {
     let pat = if let syn::Item::Macro2(mac) = item {
-        PatternDef::lex(mac.args.clone(), mac.body.clone())
+        PatternDef::parse(mac.args.clone(), mac.body.clone())
     } else {
         unimplemented!()
     };
-    let pat = pat.parse().unwrap();
+    let pat = pat.unwrap();
     /*
     let pat = match pat.parse() {
         Ok(x) => x,
@@ -0,0 +1,169 @@
+//! The constructs around a match
+
+use super::visit::Visitor;
+use crate::trace::ReTracer;
+
+/// A construct that a match can be required to be inside, or not.
+#[derive(Debug, Clone)]
+pub enum Context {
+    /// `unsafe`: an unsafe block or fn.
+    Unsafe,
+    /// `loop`: a `loop`, `while` or `for` expression.
+    Loop,
+    /// `closure`
+    Closure,
+    /// `async`: an async block or fn.
+    Async,
+    /// `impl Trait`, or `impl Trait for Type`, by the last segments of the paths.
+    Impl { trait_: String, ty: Option<String> },
+    /// `#[test] fn`
+    Test,
+}
+
+/// An item that input statements are within, which the input's trace can't show.
+#[derive(Debug, Clone)]
+pub enum Enclosing {
+    Fn {
+        unsafety: bool,
+        asyncness: bool,
+        test: bool,
+    },
+    Impl {
+        trait_: Option<String>,
+        ty: Option<String>,
+    },
+}
+
+/// The name in the last segment of a path.
+fn last_name(path: &syn::Path) -> Option<String> {
+    path.segments.iter().last().map(|seg| seg.ident.to_string())
+}
+
+fn is_test(attrs: &[syn::Attribute]) -> bool {
+    attrs.iter().any(|attr| attr.path.is_ident("test"))
+}
+
+impl Enclosing {
+    pub fn of_fn(f: &syn::ItemFn) -> Self {
+        Enclosing::Fn {
+            unsafety: f.unsafety.is_some(),
+            asyncness: f.asyncness.is_some(),
+            test: is_test(&f.attrs),
+        }
+    }
+
+    pub fn of_method(m: &syn::ImplItemMethod) -> Self {
+        Enclosing::Fn {
+            unsafety: m.sig.unsafety.is_some(),
+            asyncness: m.sig.asyncness.is_some(),
+            test: is_test(&m.attrs),
+        }
+    }
+
+    pub fn of_impl(i: &syn::ItemImpl) -> Self {
+        let ty = match &*i.self_ty {
+            syn::Type::Path(ty) => last_name(&ty.path),
+            _ => None,
+        };
+        Enclosing::Impl {
+            trait_: i.trait_.as_ref().and_then(|(_, path, _)| last_name(path)),
+            ty,
+        }
+    }
+}
+
+/// A where-clause requirement that a match be `inside` a construct, or `not inside` one.
+#[derive(Debug, Clone)]
+pub struct Inside {
+    pub negated: bool,
+    pub context: Context,
+}
+
+impl Inside {
+    /// Whether the requirement holds of a match within these expressions and items.
+    pub(crate) fn holds(&self, exprs: &[&syn::Expr], items: &[Enclosing]) -> bool {
+        use syn::Expr;
+        let in_expr = exprs.iter().any(|expr| {
+            matches!(
+                (&self.context, expr),
+                (Context::Unsafe, Expr::Unsafe(_))
+                    | (Context::Loop, Expr::Loop(_))
+                    | (Context::Loop, Expr::While(_))
+                    | (Context::Loop, Expr::ForLoop(_))
+                    | (Context::Closure, Expr::Closure(_))
+                    | (Context::Async, Expr::Async(_))
+            )
+        });
+        let in_item = items.iter().any(|item| match (&self.context, item) {
+            (Context::Unsafe, Enclosing::Fn { unsafety, .. }) => *unsafety,
+            (Context::Async, Enclosing::Fn { asyncness, .. }) => *asyncness,
+            (Context::Test, Enclosing::Fn { test, .. }) => *test,
+            (Context::Impl { trait_, ty }, Enclosing::Impl { trait_: t, ty: u }) => {
+                t.as_ref() == Some(trait_) && (ty.is_none() || ty == u)
+            }
+            _ => false,
+        });
+        (in_expr || in_item) != self.negated
+    }
+}
+
+/// Follows the trace of an input with a metavar in place of a matched expression, collecting the
+/// expressions the match is nested in, outermost first.
+pub(crate) struct Ancestors<'ast, 't> {
+    trace: ReTracer<'t>,
+    open: Vec<&'ast syn::Expr>,
+    found: Vec<&'ast syn::Expr>,
+}
+
+impl<'ast, 't> Ancestors<'ast, 't> {
+    pub fn new(trace: ReTracer<'t>) -> Self {
+        Ancestors {
+            trace,
+            open: Vec::new(),
+            found: Vec::new(),
+        }
+    }
+}
+
+impl<'ast, 't> Visitor<'ast> for Ancestors<'ast, 't> {
+    type Output = Vec<&'ast syn::Expr>;
+
+    fn open_expr(&mut self, expr: &'ast syn::Expr) -> Result<(), ()> {
+        if let Err(()) = self.trace.open_subtree() {
+            // the match
+            self.trace.consume_meta();
+            self.found = self.open.clone();
+            return Err(());
+        }
+        self.open.push(expr);
+        Ok(())
+    }
+    fn close_expr(&mut self, _: &'ast syn::Expr) {
+        self.open.pop();
+        self.trace.close_subtree().unwrap();
+    }
+
+    fn open_subtree(&mut self) {
+        self.trace.open_subtree().unwrap();
+    }
+    fn close_subtree(&mut self) {
+        self.trace.close_subtree().unwrap();
+    }
+    fn open_datum(&mut self) {
+        self.trace.open_datum();
+    }
+    fn close_datum(&mut self) {
+        self.trace.close_datum();
+    }
+    fn push_byte(&mut self, x: u8) {
+        self.trace.push_byte(x);
+    }
+    fn extend_bytes(&mut self, x: &[u8]) {
+        self.trace.extend_bytes(x);
+    }
+
+    fn finish(self) -> Self::Output {
+        self.trace.finish();
+        self.found
+    }
+}
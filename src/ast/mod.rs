@@ -1,16 +1,23 @@
 //! Operations on syn AST
 
+mod context;
 mod names;
 mod repr;
 mod visit;
 
+use self::context::Ancestors;
+pub use self::context::{Context, Enclosing, Inside};
+pub(crate) use self::visit::lit_discrim;
 use self::visit::Visitor;
 
 use crate::trace::*;
-use crate::tokens::MetaContext;
+use crate::tokens::{
+    has_nested_group, split_boundaries, split_set, split_where, Group, MetaContext, WhereClause,
+};
 
 use proc_macro2::TokenStream;
 use syn::parse::Parse;
+use std::collections::HashMap;
 
 #[derive(Debug)]
 pub struct Error;
@@ -77,9 +84,11 @@ impl IndexedTraceGenerator {
 }
 impl Visitor<'_> for IndexedTraceGenerator {
     type Output = IndexedTrace;
-    fn open_stmt(&mut self, _: &syn::Stmt) {
+    fn open_stmt(&mut self, _: &syn::Stmt) -> std::result::Result<(), ()> {
+        // statements are indexed at every depth; see `StmtLocator`
         self.trace.mark();
         self.trace.open_subtree();
+        Ok(())
     }
     fn open_subtree(&mut self) {
         self.trace.open_subtree();
@@ -104,50 +113,247 @@ impl Visitor<'_> for IndexedTraceGenerator {
     }
 }
 
+/// A statement of an input: the statements of its block, its position there, and the
+/// expressions the block is nested in, outermost first.
+struct Located<'ast> {
+    block: &'ast [syn::Stmt],
+    pos: usize,
+    exprs: Vec<&'ast syn::Expr>,
+}
+
+/// Finds the statements of an input in the order they are indexed in its trace.
+struct StmtLocator<'ast> {
+    blocks: Vec<(&'ast [syn::Stmt], usize)>,
+    exprs: Vec<&'ast syn::Expr>,
+    found: Vec<Located<'ast>>,
+}
+impl<'ast> StmtLocator<'ast> {
+    fn new(input: &'ast [syn::Stmt]) -> Self {
+        StmtLocator {
+            blocks: vec![(input, 0)],
+            exprs: Vec::new(),
+            found: Vec::new(),
+        }
+    }
+}
+impl<'ast> Visitor<'ast> for StmtLocator<'ast> {
+    type Output = Vec<Located<'ast>>;
+    fn open_block(&mut self, x: &'ast syn::Block) -> std::result::Result<(), ()> {
+        self.blocks.push((&x.stmts, 0));
+        Ok(())
+    }
+    fn close_block(&mut self, _: &'ast syn::Block) {
+        self.blocks.pop();
+    }
+    fn open_stmt(&mut self, _: &'ast syn::Stmt) -> std::result::Result<(), ()> {
+        let (block, pos) = self.blocks.last_mut().unwrap();
+        self.found.push(Located {
+            block,
+            pos: *pos,
+            exprs: self.exprs.clone(),
+        });
+        *pos += 1;
+        Ok(())
+    }
+    fn close_stmt(&mut self, _: &'ast syn::Stmt) {}
+    fn open_expr(&mut self, x: &'ast syn::Expr) -> std::result::Result<(), ()> {
+        self.exprs.push(x);
+        Ok(())
+    }
+    fn close_expr(&mut self, _: &'ast syn::Expr) {
+        self.exprs.pop();
+    }
+    fn open_subtree(&mut self) {}
+    fn close_subtree(&mut self) {}
+    fn open_datum(&mut self) {}
+    fn close_datum(&mut self) {}
+    fn push_byte(&mut self, _: u8) {}
+    fn extend_bytes(&mut self, _: &[u8]) {}
+    fn finish(self) -> Self::Output {
+        self.found
+    }
+}
+
+/// Whether a path is nothing but one ident.
+fn is_bare_path(x: &syn::Path) -> bool {
+    x.leading_colon.is_none() && x.segments.len() == 1 && x.segments[0].arguments.is_empty()
+}
+
+/// The kinds of node a placeholder can stand for.
+#[derive(Clone, Copy, PartialEq)]
+enum Node {
+    Expr,
+    Type,
+    Pat,
+    Path,
+    Stmt,
+    Block,
+    Lifetime,
+    Other,
+}
+
 struct Reconciler<'t> {
     trace: DeltaTracer<'t>,
+    // for each open subtree, its kind of node and whether it is nothing but one ident
+    open: Vec<(Node, bool)>,
+    // a placeholder was found where its kind of node can't be
+    misplaced: bool,
 }
 impl<'t> Reconciler<'t> {
     pub fn new(trace: &'t Trace) -> Self {
         let trace = DeltaTracer::new(trace);
-        Reconciler { trace }
+        Reconciler {
+            trace,
+            open: Vec::new(),
+            misplaced: false,
+        }
+    }
+
+    fn open_node(&mut self, node: Node, bare: bool) {
+        self.open_subtree();
+        *self.open.last_mut().unwrap() = (node, bare);
+    }
+
+    /// Replace the subtree `depth` levels above the closing placeholder with metavar `id`.
+    fn replace(&mut self, id: &str, depth: usize) {
+        self.trace.new.replacement.push_mvar(id.parse().unwrap());
+        self.trace.new.rollback(depth);
+        self.trace.new.close_subtree();
+    }
+
+    /// Note a placeholder that can't stand where it is, and replace it with a wildcard so the
+    /// trace stays consistent.
+    fn misplace(&mut self) {
+        self.misplaced = true;
+        self.replace(&WILDCARD.to_string(), 0);
     }
 }
 impl<'t, 'a> Visitor<'a> for Reconciler<'t> {
-    type Output = Trace;
+    type Output = Result<Trace>;
+    fn open_expr(&mut self, x: &syn::Expr) -> std::result::Result<(), ()> {
+        let bare = match x {
+            syn::Expr::Path(x) => x.attrs.is_empty() && x.qself.is_none() && is_bare_path(&x.path),
+            _ => false,
+        };
+        self.open_node(Node::Expr, bare);
+        Ok(())
+    }
+    fn open_type(&mut self, x: &syn::Type) -> std::result::Result<(), ()> {
+        let bare = match x {
+            syn::Type::Path(x) => x.qself.is_none() && is_bare_path(&x.path),
+            _ => false,
+        };
+        self.open_node(Node::Type, bare);
+        Ok(())
+    }
+    fn open_pat(&mut self, x: &syn::Pat) -> std::result::Result<(), ()> {
+        let bare = match x {
+            syn::Pat::Ident(x) => {
+                x.by_ref.is_none() && x.mutability.is_none() && x.subpat.is_none()
+            }
+            _ => false,
+        };
+        self.open_node(Node::Pat, bare);
+        Ok(())
+    }
+    fn open_path(&mut self, x: &syn::Path) -> std::result::Result<(), ()> {
+        self.open_node(Node::Path, is_bare_path(x));
+        Ok(())
+    }
+    fn open_stmt(&mut self, _: &syn::Stmt) -> std::result::Result<(), ()> {
+        self.open_node(Node::Stmt, false);
+        Ok(())
+    }
+    fn open_block(&mut self, _: &syn::Block) -> std::result::Result<(), ()> {
+        self.open_node(Node::Block, false);
+        Ok(())
+    }
+    fn open_lifetime(&mut self, _: &syn::Lifetime) -> std::result::Result<(), ()> {
+        self.open_node(Node::Lifetime, false);
+        Ok(())
+    }
+
     fn close_ident(&mut self, x: &syn::Ident) {
+        self.open.pop();
         if let Ok(()) = self.trace.close_subtree() {
             return;
         }
         let x = x.to_string();
-        let x_id = x.trim_left_matches("IDENT_");
-        let x_expr = x.trim_left_matches("EXPR_");
-        if x_id != x {
-            // replace current
-            self.trace
-                .new
-                .replacement
-                .push_mvar(u8::from_str_radix(x_id, 10).unwrap());
-            self.trace.new.rollback(0);
-            self.trace.new.close_subtree();
-        } else if x_expr != x {
-            // replace parent
-            self.trace
-                .new
-                .replacement
-                .push_mvar(u8::from_str_radix(x_expr, 10).unwrap());
-            self.trace.new.rollback(1);
-            self.trace.new.close_subtree();
-        } else {
-            unreachable!("no such placeholder");
+        if let Some(id) = x.strip_prefix("INFER_") {
+            // stand for the largest node that is nothing but the placeholder
+            let depth = self.open.iter().rev().take_while(|&&(_, bare)| bare).count();
+            self.replace(id, depth);
+            return;
+        }
+        // placeholder idents are nested `depth` subtrees deep in the node they stand for
+        let (id, depth, node) = [
+            ("IDENT_", 0, Node::Other),
+            ("PAT_", 1, Node::Pat),
+            ("PATH_", 1, Node::Path),
+            ("LIFETIME_", 1, Node::Lifetime),
+            ("EXPR_", 2, Node::Expr),
+            ("TYPE_", 2, Node::Type),
+            ("STMT_", 3, Node::Stmt),
+            ("BLOCK_", 4, Node::Block),
+        ]
+        .iter()
+        .find_map(|&(prefix, depth, node)| Some((x.strip_prefix(prefix)?, depth, node)))
+        .expect("no such placeholder");
+        // and must be where that kind of node is, as all there is of it
+        let misplaced = depth > 0
+            && match self.open.len().checked_sub(depth) {
+                Some(i) => {
+                    let whole = |&(node, bare): &(Node, bool)| {
+                        bare || [Node::Stmt, Node::Block, Node::Lifetime].contains(&node)
+                    };
+                    self.open[i].0 != node || !self.open[i..].iter().all(whole)
+                }
+                None => true,
+            };
+        if misplaced {
+            return self.misplace();
+        }
+        self.replace(id, depth);
+    }
+    fn close_lit(&mut self, x: &syn::Lit) {
+        self.open.pop();
+        if let Ok(()) = self.trace.close_subtree() {
+            return;
+        }
+        let x = match x {
+            syn::Lit::Str(x) => x.value(),
+            _ => unreachable!("no such placeholder"),
+        };
+        let id = x.strip_prefix("LIT_").expect("no such placeholder");
+        self.replace(id, 0);
+    }
+    fn close_tt(&mut self, x: &proc_macro2::TokenTree) {
+        self.open.pop();
+        if let Ok(()) = self.trace.close_subtree() {
+            return;
+        }
+        // inside a macro invocation, a placeholder can only stand for one token tree: only tt and
+        // ident metavars can be used there
+        let x = match x {
+            proc_macro2::TokenTree::Ident(x) => x.to_string(),
+            _ => return self.misplace(),
+        };
+        match ["TT_", "IDENT_", "INFER_"]
+            .iter()
+            .find_map(|prefix| x.strip_prefix(prefix))
+        {
+            Some(id) => self.replace(id, 0),
+            None => self.misplace(),
         }
     }
 
     fn open_subtree(&mut self) {
         self.trace.open_subtree().unwrap();
+        self.open.push((Node::Other, false));
     }
     fn close_subtree(&mut self) {
         self.trace.close_subtree().unwrap();
+        self.open.pop();
     }
     fn open_datum(&mut self) {
         self.trace.open_datum();
@@ -163,7 +369,10 @@ impl<'t, 'a> Visitor<'a> for Reconciler<'t> {
     }
 
     fn finish(self) -> Self::Output {
-        self.trace.finish()
+        if self.misplaced {
+            return Err(Error);
+        }
+        Ok(self.trace.finish())
     }
 }
 
@@ -171,6 +380,19 @@ impl<'t, 'a> Visitor<'a> for Reconciler<'t> {
 pub enum Binding<'ast> {
     Expr(&'ast syn::Expr),
     Ident(&'ast syn::Ident),
+    Type(&'ast syn::Type),
+    Pat(&'ast syn::Pat),
+    Lit(&'ast syn::Lit),
+    Stmt(&'ast syn::Stmt),
+    Block(&'ast syn::Block),
+    Path(&'ast syn::Path),
+    Lifetime(&'ast syn::Lifetime),
+    // token streams are unparsed, so macro invocations don't contain borrowable token trees
+    Tt(proc_macro2::TokenTree),
+    /// A metavar in a repetition group binds once per repetition.
+    Repeat(Vec<Binding<'ast>>),
+    /// An optional metavar whose syntax isn't there.
+    Absent,
 }
 
 pub struct Bindings<'ast> {
@@ -187,37 +409,96 @@ impl<'ast, 't> Binder<'ast, 't> {
             bindings: Vec::new(),
         }
     }
-}
 
-impl<'ast, 't> Visitor<'ast> for Binder<'ast, 't> {
-    type Output = Bindings<'ast>;
+    /// Collect each binding of these metavars, rather than only the first.
+    pub fn repeating(mut self, mvars: &[u8]) -> Self {
+        for &x in mvars {
+            let x = usize::from(x) - 1;
+            if x >= self.bindings.len() {
+                self.bindings.resize(x + 1, None);
+            }
+            self.bindings[x] = Some(Binding::Repeat(Vec::new()));
+        }
+        self
+    }
 
-    fn open_expr(&mut self, expr: &'ast syn::Expr) -> std::result::Result<(), ()> {
-        if let Err(()) = self.trace.open_subtree() {
-            let x = usize::from(self.trace.consume_meta()) - 1;
+    /// Bind these metavars to `Absent` unless they occur in the input.
+    pub fn optional(mut self, mvars: &[u8]) -> Self {
+        for &x in mvars {
+            let x = usize::from(x) - 1;
             if x >= self.bindings.len() {
                 self.bindings.resize(x + 1, None);
             }
             if self.bindings[x].is_none() {
-                self.bindings[x] = Some(Binding::Expr(expr));
+                self.bindings[x] = Some(Binding::Absent);
             }
-            return Err(());
         }
-        Ok(())
+        self
     }
-    fn open_ident(&mut self, ident: &'ast syn::Ident) -> std::result::Result<(), ()> {
+
+    /// If the pattern has a metavar where the input has this node, bind the node.
+    fn open_bindable(&mut self, binding: Binding<'ast>) -> std::result::Result<(), ()> {
         if let Err(()) = self.trace.open_subtree() {
-            let x = usize::from(self.trace.consume_meta()) - 1;
+            let x = self.trace.consume_meta();
+            if x == WILDCARD {
+                return Err(());
+            }
+            let x = usize::from(x) - 1;
             if x >= self.bindings.len() {
                 self.bindings.resize(x + 1, None);
             }
-            if self.bindings[x].is_none() {
-                self.bindings[x] = Some(Binding::Ident(ident));
+            match &mut self.bindings[x] {
+                Some(Binding::Repeat(repeats)) => repeats.push(binding),
+                unbound @ None | unbound @ Some(Binding::Absent) => *unbound = Some(binding),
+                Some(_) => (),
             }
             return Err(());
         }
         Ok(())
     }
+}
+
+impl<'ast, 't> Visitor<'ast> for Binder<'ast, 't> {
+    type Output = Bindings<'ast>;
+
+    fn open_expr(&mut self, expr: &'ast syn::Expr) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Expr(expr))
+    }
+    fn open_ident(&mut self, ident: &'ast syn::Ident) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Ident(ident))
+    }
+    fn open_type(&mut self, ty: &'ast syn::Type) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Type(ty))
+    }
+    fn open_pat(&mut self, pat: &'ast syn::Pat) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Pat(pat))
+    }
+    fn open_lit(&mut self, lit: &'ast syn::Lit) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Lit(lit))
+    }
+    fn open_stmt(&mut self, stmt: &'ast syn::Stmt) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Stmt(stmt))
+    }
+    fn open_block(&mut self, block: &'ast syn::Block) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Block(block))
+    }
+    fn open_path(&mut self, path: &'ast syn::Path) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Path(path))
+    }
+    fn open_lifetime(&mut self, lifetime: &'ast syn::Lifetime) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Lifetime(lifetime))
+    }
+    fn open_tt(&mut self, tt: &proc_macro2::TokenTree) -> std::result::Result<(), ()> {
+        self.open_bindable(Binding::Tt(tt.clone()))
+    }
+    fn open_field_value(&mut self, _: &'ast syn::FieldValue) -> std::result::Result<(), ()> {
+        // a field skipped by a pattern that matches some fields in any order
+        if let Err(()) = self.trace.open_subtree() {
+            assert_eq!(self.trace.consume_meta(), WILDCARD);
+            return Err(());
+        }
+        Ok(())
+    }
 
     fn open_subtree(&mut self) {
         self.trace.open_subtree().unwrap();
@@ -240,8 +521,13 @@ impl<'ast, 't> Visitor<'ast> for Binder<'ast, 't> {
 
     fn finish(self) -> Self::Output {
         self.trace.finish();
+        // a metavar that only occurs in a `not` sub-pattern binds nothing
         Bindings {
-            binds: self.bindings.into_iter().map(|x| x.unwrap()).collect(),
+            binds: self
+                .bindings
+                .into_iter()
+                .map(|x| x.unwrap_or(Binding::Absent))
+                .collect(),
         }
     }
 }
@@ -254,46 +540,319 @@ impl Parse for Stmts {
 }
 
 /// A pattern definition.
+#[allow(clippy::large_enum_variant)]
 pub enum PatternDef {
     StmtSeq {
         nodes: Vec<syn::Stmt>,
         ids: Vec<syn::Stmt>,
+        /// `^`: the statements start the block.
+        from_start: bool,
+        /// `$`: the statements end the block; the last may be its tail expression.
+        to_end: bool,
+        common: Common,
+    },
+    /// Statements that may occur in any order in a block, among any others.
+    StmtSet {
+        nodes: Vec<syn::Stmt>,
+        ids: Vec<syn::Stmt>,
+        common: Common,
     },
     Expr {
         nodes: syn::Expr,
         ids: syn::Expr,
+        common: Common,
     },
 }
 
+/// What every kind of pattern definition has besides its syntax: where its groups are, and what
+/// else a match must satisfy.
+#[derive(Default)]
+pub struct Common {
+    repetitions: Vec<Repetition>,
+    alternations: Vec<Alternation>,
+    gaps: Vec<usize>,
+    unordered: Vec<Unordered>,
+    chains: Vec<Chain>,
+    optional: Vec<u8>,
+    constraints: Vec<Constraint>,
+    tests: Vec<ValueTest>,
+    contexts: Vec<Inside>,
+    counts: Vec<UseCount>,
+    negations: Vec<(Scope, Trace)>,
+    mvars: HashMap<String, u8>,
+}
+
+/// Whether a statement is nothing but a stmt placeholder, which stands for any statement rather
+/// than an expression.
+fn is_stmt_placeholder(x: &syn::Stmt) -> bool {
+    match x {
+        syn::Stmt::Expr(syn::Expr::Path(x)) if is_bare_path(&x.path) => {
+            x.path.segments[0].ident.to_string().starts_with("STMT_")
+        }
+        _ => false,
+    }
+}
+
+fn parse_stmts(nodes: TokenStream, ids: TokenStream) -> Result<(Vec<syn::Stmt>, Vec<syn::Stmt>)> {
+    let nodes: Stmts = syn::parse2(nodes).map_err(|_| Error)?;
+    let ids: Stmts = syn::parse2(ids).expect("if <nodes> succeeded <ids> must");
+    Ok((nodes.0, ids.0))
+}
+
 impl PatternDef {
     pub fn parse(args: TokenStream, body: TokenStream) -> Result<Self> { 
-        let (nodes, ids) = MetaContext::new(args).apply(body);
-        let nodes: Stmts = syn::parse2(nodes).map_err(|_| Error)?;
-        let ids: Stmts = syn::parse2(ids).expect("if <nodes> succeeded <ids> must");
-        let (mut nodes, mut ids) = (nodes.0, ids.0);
-        if nodes.len() == 1 {
-            if let syn::Stmt::Expr(_) = nodes[0] {
-                if let (syn::Stmt::Expr(nodes), syn::Stmt::Expr(ids)) =
-                    (nodes.remove(0), ids.remove(0))
-                {
-                    return Ok(PatternDef::Expr { nodes, ids });
+        let (body, clause) = split_where(body);
+        let (body, from_start, to_end) = split_boundaries(body);
+        let (body, set) = match split_set(&body) {
+            Some(stmts) => (stmts, true),
+            None => (body, false),
+        };
+        if set && (from_start || to_end) {
+            return Err(Error);
+        }
+        let mut ctx = MetaContext::new(args)?;
+        ctx.declare_inline(&body)?;
+        let mut clause = if clause.is_empty() {
+            WhereClause::default()
+        } else {
+            ctx.where_clause(clause)?
+        };
+        for (_, sub) in &clause.negations {
+            ctx.declare_inline(sub)?;
+        }
+        let subs = clause.negations.iter().map(|(_, sub)| sub);
+        ctx.infer_idents(&std::iter::once(&body).chain(subs).collect::<Vec<_>>())?;
+        ctx.check_used()?;
+        clause.tests.extend(ctx.name_tests()?);
+        for test in &clause.tests {
+            if let Value::Name(regex) | Value::Str { regex, .. } = &test.value {
+                regex::Regex::new(regex).map_err(|_| Error)?;
+            }
+        }
+        let negations = clause
+            .negations
+            .iter()
+            .map(|(scope, sub)| Ok((*scope, PatternDef::negation(&ctx, sub.clone(), &clause)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut def = PatternDef::build(&ctx, body, set, from_start, to_end)?;
+        def.require(clause);
+        def.common_mut().negations = negations;
+        Ok(def)
+    }
+
+    /// Compile the sub-pattern of a `not` block: statements, or else a match arm or a pattern. A
+    /// sub-pattern is held to the same relations and tests, which can involve its metavars.
+    fn negation(ctx: &MetaContext, sub: TokenStream, clause: &WhereClause) -> Result<Trace> {
+        let (constraints, tests) = (clause.constraints.clone(), clause.tests.clone());
+        if let Ok(mut def) = PatternDef::build(ctx, sub.clone(), false, false, false) {
+            def.require(WhereClause {
+                constraints,
+                tests,
+                ..WhereClause::default()
+            });
+            return Ok(def.compile().into_trace());
+        }
+        let (nodes, ids, groups) = ctx.apply(sub, None)?;
+        if !groups.is_empty() {
+            return Err(Error);
+        }
+        let trace = if let Ok(nodes) = syn::parse2::<syn::Arm>(nodes.clone()) {
+            let ids: syn::Arm = syn::parse2(ids).expect("if <nodes> succeeded <ids> must");
+            Reconciler::new(&TraceGenerator::apply(&nodes)).visit(&ids)?
+        } else {
+            let nodes: syn::Pat = syn::parse2(nodes).map_err(|_| Error)?;
+            let ids: syn::Pat = syn::parse2(ids).expect("if <nodes> succeeded <ids> must");
+            Reconciler::new(&TraceGenerator::apply(&nodes)).visit(&ids)?
+        };
+        Ok(trace
+            .with_optional(&ctx.optional_ids()?)
+            .with_constraints(&constraints)
+            .with_tests(&tests))
+    }
+
+    /// Compile a pattern body, without any `where` clause.
+    fn build(
+        ctx: &MetaContext,
+        body: TokenStream,
+        set: bool,
+        from_start: bool,
+        to_end: bool,
+    ) -> Result<Self> {
+        if has_nested_group(&body) {
+            return Err(Error);
+        }
+        let (nodes, ids, groups) = ctx.apply(body.clone(), None)?;
+        let (mut nodes, mut ids) = parse_stmts(nodes, ids)?;
+        let common = Common {
+            optional: ctx.optional_ids()?,
+            mvars: ctx.ids()?,
+            ..Common::default()
+        };
+        let mut def = if set {
+            PatternDef::StmtSet { nodes, ids, common }
+        } else if let ([syn::Stmt::Expr(_)], false, false, false) =
+            (&nodes[..], from_start, to_end, is_stmt_placeholder(&ids[0]))
+        {
+            match (nodes.remove(0), ids.remove(0)) {
+                (syn::Stmt::Expr(nodes), syn::Stmt::Expr(ids)) => {
+                    PatternDef::Expr { nodes, ids, common }
+                }
+                _ => unreachable!(),
+            }
+        } else {
+            PatternDef::StmtSeq {
+                nodes,
+                ids,
+                from_start,
+                to_end,
+                common,
+            }
+        };
+        // every placeholder must be where its kind of node can be
+        let first = def.trace()?;
+        if groups.is_empty() {
+            return Ok(def);
+        }
+        // locate each group by comparison with traces that expand it differently
+        let (mut reps, mut alts, mut gs) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut us, mut cs) = (Vec::new(), Vec::new());
+        for (i, group) in groups.iter().enumerate() {
+            match group {
+                Group::Repetition { op, separator } => {
+                    // compare with two copies, or for an optional group, none
+                    let copies = if *op == '?' { 0 } else { 2 };
+                    let other = def.trace_expansion(ctx, &body, (i, copies))?;
+                    let separator = match separator {
+                        Some(sep) => TraceGenerator::apply(sep),
+                        None => TraceGenerator::default().finish(),
+                    };
+                    let (nodes, ids) = ctx.apply_marked(body.clone(), i)?;
+                    let marked = def.reconcile(nodes, ids)?;
+                    let rep = if copies == 0 {
+                        Repetition::locate(*op as u8, &other, &first, &separator, Some(&marked))
+                    } else {
+                        Repetition::locate(*op as u8, &first, &other, &separator, Some(&marked))
+                    };
+                    reps.push(rep.ok_or(Error)?);
+                }
+                Group::Alternation { branches } => {
+                    let others = (1..*branches)
+                        .map(|branch| def.trace_expansion(ctx, &body, (i, branch)))
+                        .collect::<Result<Vec<_>>>()?;
+                    alts.push(Alternation::locate(&first, &others).ok_or(Error)?);
+                }
+                Group::Gap => {
+                    // compare with a wildcard statement in the gap
+                    let other = def.trace_expansion(ctx, &body, (i, 1))?;
+                    gs.push(locate_gap(&first, &other).ok_or(Error)?);
+                }
+                Group::Fields => {
+                    // compare with a wildcard field after the others, and before them
+                    let after = def.trace_expansion(ctx, &body, (i, 1))?;
+                    let before = def.trace_expansion(ctx, &body, (i, 2))?;
+                    us.push(Unordered::locate(&first, &after, &before).ok_or(Error)?);
+                }
+                Group::Chain => {
+                    // compare with a wildcard call around the receiver
+                    let other = def.trace_expansion(ctx, &body, (i, 1))?;
+                    cs.push(Chain::locate(&first, &other).ok_or(Error)?);
                 }
-                unreachable!();
             }
         }
-        Ok(PatternDef::StmtSeq { nodes, ids })
+        let common = def.common_mut();
+        common.repetitions = reps;
+        common.alternations = alts;
+        common.gaps = gs;
+        common.unordered = us;
+        common.chains = cs;
+        let grouped = def.grouped_trace().ok_or(Error)?;
+        if let PatternDef::StmtSet { .. } = def {
+            // statements can be grouped within themselves, but not with each other
+            grouped.as_set().ok_or(Error)?;
+        }
+        Ok(def)
+    }
+
+    fn common(&self) -> &Common {
+        match self {
+            PatternDef::StmtSeq { common, .. }
+            | PatternDef::StmtSet { common, .. }
+            | PatternDef::Expr { common, .. } => common,
+        }
+    }
+
+    fn common_mut(&mut self) -> &mut Common {
+        match self {
+            PatternDef::StmtSeq { common, .. }
+            | PatternDef::StmtSet { common, .. }
+            | PatternDef::Expr { common, .. } => common,
+        }
+    }
+
+    /// Impose the relations, tests, contexts and use counts of a `where` clause.
+    fn require(&mut self, clause: WhereClause) {
+        let common = self.common_mut();
+        common.constraints = clause.constraints;
+        common.tests = clause.tests;
+        common.contexts = clause.contexts;
+        common.counts = clause.counts;
+    }
+
+    /// Trace the pattern body with one group expanded differently; see `MetaContext::apply`.
+    fn trace_expansion(
+        &self,
+        ctx: &MetaContext,
+        body: &TokenStream,
+        expanded: (usize, usize),
+    ) -> Result<Trace> {
+        let (nodes, ids, _) = ctx.apply(body.clone(), Some(expanded))?;
+        self.reconcile(nodes, ids)
+    }
+
+    /// Trace an expansion of the pattern body. The result must be the same kind of fragment as
+    /// this pattern.
+    fn reconcile(&self, nodes: TokenStream, ids: TokenStream) -> Result<Trace> {
+        let (nodes, ids) = parse_stmts(nodes, ids)?;
+        match self {
+            PatternDef::StmtSeq { .. } | PatternDef::StmtSet { .. } => {
+                Reconciler::new(&TraceGenerator::apply(&nodes[..])).visit(&ids[..])
+            }
+            PatternDef::Expr { .. } => match (&nodes[..], &ids[..]) {
+                ([syn::Stmt::Expr(nodes)], [syn::Stmt::Expr(ids)]) => {
+                    Reconciler::new(&TraceGenerator::apply(nodes)).visit(ids)
+                }
+                _ => Err(Error),
+            },
+        }
     }
 }
 
+/// A test of a metavar's binding, for conditions patterns can't express.
+pub type Predicate = Box<dyn for<'ast> Fn(&Binding<'ast>) -> bool>;
+
 /// A compiled pattern.
 pub enum Pattern {
-    StmtSeq { trace: Trace },
-    Expr { trace: Trace },
+    StmtSeq(Compiled),
+    StmtSet(Compiled),
+    Expr(Compiled),
 }
 
-enum MatchesInner<'p, 'it> {
+/// What every kind of compiled pattern has.
+pub struct Compiled {
+    trace: Trace,
+    mvars: HashMap<String, u8>,
+    predicates: Vec<(usize, Predicate)>,
+    contexts: Vec<Inside>,
+    counts: Vec<UseCount>,
+    negations: Vec<(Scope, Trace)>,
+}
+
+enum MatchesInner<'p, 'i, 'it> {
     StmtSeq {
         matches: crate::trace::ToplevelMatches<'p, 'it>,
+        set: bool,
+        // each statement of the input, at any depth
+        stmts: Vec<Located<'i>>,
     },
     Expr {
         matches: crate::trace::InternalMatches<'p, 'it>,
@@ -304,54 +863,168 @@ enum MatchesInner<'p, 'it> {
 pub struct Match {
     pub context: String,
     pub bindings: String,
+    /// In a statement sequence, the indexes of the first matched statement and of the first
+    /// statement after each gap; in a statement set, the index of each matched statement.
+    pub anchors: Vec<usize>,
+    /// The number of uses of a metavar's binding counted for each `used` requirement, in order.
+    pub uses: Vec<usize>,
 }
 
 pub struct Matches<'p, 'i, 'it> {
-    inner: MatchesInner<'p, 'it>,
-    pattern: &'p Trace,
+    inner: MatchesInner<'p, 'i, 'it>,
+    pattern: &'p Compiled,
     input: &'i [syn::Stmt],
+    input_trace: &'it IndexedTrace,
+    items: &'i [Enclosing],
+}
+
+impl<'i> Matches<'_, 'i, '_> {
+    /// Note the items the input is within, for the pattern's `inside` requirements. Otherwise, the
+    /// input is taken to be within none.
+    pub fn within(mut self, items: &'i [Enclosing]) -> Self {
+        self.items = items;
+        self
+    }
+}
+
+fn accepts(predicates: &[(usize, Predicate)], bindings: &Bindings) -> bool {
+    // a metavar that only occurs in a `not` sub-pattern has no binding to test
+    predicates
+        .iter()
+        .all(|(i, predicate)| match bindings.binds.get(*i) {
+            None | Some(Binding::Absent) => true,
+            Some(binding) => predicate(binding),
+        })
 }
 
 impl Iterator for Matches<'_, '_, '_> {
     type Item = Match;
     fn next(&mut self) -> Option<Self::Item> {
+        let (items, input_trace) = (self.items, self.input_trace);
+        let Compiled {
+            trace,
+            predicates,
+            contexts,
+            counts,
+            negations,
+            ..
+        } = self.pattern;
+        let excluded = |site: &Site| {
+            negations
+                .iter()
+                .any(|(scope, sub)| sub.occurs(input_trace, site, *scope))
+        };
+        let counted = |site: &Site| {
+            let uses: Vec<_> = counts
+                .iter()
+                .map(|c| site.uses(input_trace, c.mvar, c.scope))
+                .collect();
+            let allowed = counts.iter().zip(&uses).all(|(c, &n)| c.allows(n));
+            if allowed {
+                Some(uses)
+            } else {
+                None
+            }
+        };
         match &mut self.inner {
-            MatchesInner::StmtSeq { matches } => matches.next().map(|m| {
-                let mut context = "[".to_owned();
-                let mut first = true;
-                for s in &self.input[..m] {
+            MatchesInner::StmtSeq { matches, set, stmts } => {
+                for (i, matched, found, site) in matches {
+                    let Located {
+                        block: input,
+                        pos: m,
+                        ref exprs,
+                    } = stmts[i];
+                    if !contexts.iter().all(|c| c.holds(exprs, items)) {
+                        continue;
+                    }
+                    if excluded(&site) {
+                        continue;
+                    }
+                    let uses = match counted(&site) {
+                        Some(uses) => uses,
+                        None => continue,
+                    };
+                    let len = matched.toplevel_len();
+                    let binder = Binder::new(ReTracer::new(&matched))
+                        .repeating(&trace.repeated_mvars())
+                        .optional(&trace.optional_mvars());
+                    let bindings = binder.visit(&input[m..m + len]);
+                    if !accepts(predicates, &bindings) {
+                        continue;
+                    }
+                    let mut context = "[".to_owned();
+                    let mut first = true;
+                    for s in &input[..m] {
+                        if !first {
+                            context.push(',');
+                        }
+                        first = false;
+                        context.push_str(&repr::input_json(s));
+                    }
                     if !first {
-                        context.push_str(",");
+                        context.push(',');
                     }
-                    first = false;
-                    context.push_str(&repr::input_json(s));
-                }
-                if !first {
-                    context.push_str(",");
+                    context.push_str("\"$1\"");
+                    for s in &input[m + len..] {
+                        context.push(',');
+                        context.push_str(&repr::input_json(s));
+                    }
+                    context.push(']');
+                    let bindings = repr::bindings_json(&bindings);
+                    let found = found.into_iter().map(|n| m + n);
+                    let anchors = if *set {
+                        found.collect()
+                    } else {
+                        std::iter::once(m).chain(found).collect()
+                    };
+                    return Some(Match {
+                        context,
+                        bindings,
+                        anchors,
+                        uses,
+                    });
                 }
-                context.push_str("\"$1\"");
-                for s in &self.input[m + self.pattern.toplevel_len()..] {
-                    context.push_str(",");
-                    context.push_str(&repr::input_json(s));
+                None
+            }
+            MatchesInner::Expr { matches } => {
+                for (m, matched, site) in matches {
+                    let extracted = Binder::new(ReTracer::new(&m)).visit(self.input);
+                    // a subtree of another node type can have the same shape as an expression
+                    let ex = match extracted.binds[0] {
+                        Binding::Expr(ex) => ex,
+                        _ => continue,
+                    };
+                    if excluded(&site) {
+                        continue;
+                    }
+                    let uses = match counted(&site) {
+                        Some(uses) => uses,
+                        None => continue,
+                    };
+                    if !contexts.is_empty() {
+                        let exprs = Ancestors::new(ReTracer::new(&m)).visit(self.input);
+                        if !contexts.iter().all(|c| c.holds(&exprs, items)) {
+                            continue;
+                        }
+                    }
+                    let context = repr::pattern_json(&m, self.input);
+                    let bindings = Binder::new(ReTracer::new(&matched))
+                        .repeating(&trace.repeated_mvars())
+                        .optional(&trace.optional_mvars())
+                        .visit(ex);
+                    if !accepts(predicates, &bindings) {
+                        continue;
+                    }
+                    let bindings = repr::bindings_json(&bindings);
+                    return Some(Match {
+                        context,
+                        bindings,
+                        anchors: Vec::new(),
+                        uses,
+                    });
                 }
-                context.push_str("]");
-                let binder = Binder::new(ReTracer::new(self.pattern));
-                let bindings = binder.visit(&self.input[m..m + self.pattern.toplevel_len()]);
-                let bindings = repr::bindings_json(&bindings);
-                Match { context, bindings }
-            }),
-            MatchesInner::Expr { matches } => matches.next().map(|m| {
-                let context = repr::pattern_json(&m, self.input);
-                let extracted = Binder::new(ReTracer::new(&m)).visit(self.input);
-                let bindings = if let crate::ast::Binding::Expr(ex) = extracted.binds[0] {
-                    Binder::new(ReTracer::new(&self.pattern)).visit(ex)
-                } else {
-                    unreachable!()
-                };
-                
-                let bindings = repr::bindings_json(&bindings);
-                Match { context, bindings }
-            }),
+                None
+            }
         }
     }
 }
@@ -362,26 +1035,69 @@ impl Pattern {
         input: &'i [syn::Stmt],
         input_trace: &'it crate::trace::IndexedTrace,
     ) -> Matches<'p, 'i, 'it> {
-        let (inner, pattern) = match self {
-            Pattern::StmtSeq { trace } => (
+        let (inner, compiled) = match self {
+            Pattern::StmtSeq(compiled) => (
                 MatchesInner::StmtSeq {
-                    matches: trace.toplevel_matches(input_trace),
+                    matches: compiled.trace.toplevel_matches(input_trace),
+                    set: false,
+                    stmts: StmtLocator::new(input).visit(input),
                 },
-                trace,
+                compiled,
             ),
-            Pattern::Expr { trace } => (
+            Pattern::StmtSet(compiled) => (
+                MatchesInner::StmtSeq {
+                    matches: compiled.trace.toplevel_matches(input_trace),
+                    set: true,
+                    stmts: StmtLocator::new(input).visit(input),
+                },
+                compiled,
+            ),
+            Pattern::Expr(compiled) => (
                 MatchesInner::Expr {
-                    matches: trace.internal_matches(input_trace),
+                    matches: compiled.trace.internal_matches(input_trace),
                 },
-                trace,
+                compiled,
             ),
         };
         Matches {
             inner,
-            pattern,
+            pattern: compiled,
             input,
+            input_trace,
+            items: &[],
+        }
+    }
+
+    fn compiled_mut(&mut self) -> &mut Compiled {
+        match self {
+            Pattern::StmtSeq(compiled) | Pattern::StmtSet(compiled) | Pattern::Expr(compiled) => {
+                compiled
+            }
+        }
+    }
+
+    fn into_trace(self) -> Trace {
+        match self {
+            Pattern::StmtSeq(compiled) | Pattern::StmtSet(compiled) | Pattern::Expr(compiled) => {
+                compiled.trace
+            }
         }
     }
+
+    /// Only match where the named metavar's binding satisfies a predicate. Fails if the pattern
+    /// has no such metavar. Where the metavar binds nothing, because it is optional and absent or
+    /// only occurs in a `not` sub-pattern, the predicate isn't tested.
+    pub fn with_predicate<F>(mut self, mvar: &str, predicate: F) -> Result<Self>
+    where
+        F: for<'ast> Fn(&Binding<'ast>) -> bool + 'static,
+    {
+        let compiled = self.compiled_mut();
+        let id = *compiled.mvars.get(mvar).ok_or(Error)?;
+        compiled
+            .predicates
+            .push((usize::from(id) - 1, Box::new(predicate)));
+        Ok(self)
+    }
 }
 
 pub struct Input {
@@ -405,37 +1121,82 @@ impl Input {
 }
 
 impl PatternDef {
-    fn trace(&self) -> Trace {
+    fn trace(&self) -> Result<Trace> {
         match self {
-            PatternDef::StmtSeq { nodes, ids } => Reconciler::new(&TraceGenerator::apply(&nodes[..])).visit(&ids[..]),
-            PatternDef::Expr { nodes, ids } => Reconciler::new(&TraceGenerator::apply(nodes)).visit(ids),
+            PatternDef::StmtSeq { nodes, ids, .. } | PatternDef::StmtSet { nodes, ids, .. } => {
+                Reconciler::new(&TraceGenerator::apply(&nodes[..])).visit(&ids[..])
+            }
+            PatternDef::Expr { nodes, ids, .. } => {
+                Reconciler::new(&TraceGenerator::apply(nodes)).visit(ids)
+            }
         }
     }
 
+    /// The pattern's trace with its groups marked, if they don't overlap.
+    fn grouped_trace(&self) -> Option<Trace> {
+        let common = self.common();
+        let trace = self.trace().expect("checked by parse");
+        trace.with_groups(
+            &common.repetitions,
+            &common.alternations,
+            &common.gaps,
+            &common.unordered,
+            &common.chains,
+        )
+    }
+
     pub fn compile(&self) -> Pattern {
+        let common = self.common();
+        let grouped = self.grouped_trace().expect("checked by parse");
+        let trace = match self {
+            PatternDef::StmtSeq {
+                from_start, to_end, ..
+            } => grouped.with_boundaries(*from_start, *to_end),
+            PatternDef::StmtSet { .. } => grouped.as_set().expect("checked by parse"),
+            PatternDef::Expr { .. } => grouped,
+        };
+        let compiled = Compiled {
+            trace: trace
+                .with_optional(&common.optional)
+                .with_constraints(&common.constraints)
+                .with_tests(&common.tests),
+            mvars: common.mvars.clone(),
+            predicates: Vec::new(),
+            contexts: common.contexts.clone(),
+            counts: common.counts.clone(),
+            negations: common.negations.clone(),
+        };
         match self {
-            PatternDef::StmtSeq { .. } => Pattern::StmtSeq { trace: self.trace() },
-            PatternDef::Expr { .. } => Pattern::Expr { trace: self.trace() },
+            PatternDef::StmtSeq { .. } => Pattern::StmtSeq(compiled),
+            PatternDef::StmtSet { .. } => Pattern::StmtSet(compiled),
+            PatternDef::Expr { .. } => Pattern::Expr(compiled),
         }
     }
 
     pub fn debug_tree_repr(&self) -> String {
+        let trace = self.trace().expect("checked by parse");
         match self {
-            PatternDef::StmtSeq { ids, .. } => repr::pattern_json(&self.trace(), &ids[..]),
-            PatternDef::Expr { ids, .. } => repr::pattern_json(&self.trace(), ids),
+            PatternDef::StmtSeq { ids, .. } | PatternDef::StmtSet { ids, .. } => {
+                repr::pattern_json(&trace, &ids[..])
+            }
+            PatternDef::Expr { ids, .. } => repr::pattern_json(&trace, ids),
         }
     }
 
     pub fn debug_flat_repr(&self) -> String {
+        let trace = self.trace().expect("checked by parse");
         match self {
-            PatternDef::StmtSeq { ids, .. } => repr::pattern_flat(&self.trace(), &ids[..]),
-            PatternDef::Expr { ids, .. } => repr::pattern_flat(&self.trace(), ids),
+            PatternDef::StmtSeq { ids, .. } | PatternDef::StmtSet { ids, .. } => {
+                repr::pattern_flat(&trace, &ids[..])
+            }
+            PatternDef::Expr { ids, .. } => repr::pattern_flat(&trace, ids),
         }
     }
 
     pub fn fragment(&self) -> String {
         match self {
             PatternDef::StmtSeq { .. } => "StmtSeq".to_owned(),
+            PatternDef::StmtSet { .. } => "StmtSet".to_owned(),
             PatternDef::Expr { .. } => "Expr".to_owned(),
         }
     }
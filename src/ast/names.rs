@@ -50,6 +50,57 @@ impl Discrim for syn::Expr {
     }
 }
 
+impl Discrim for syn::Type {
+    fn discrim(&self) -> &'static str {
+        use syn::Type::*;
+        match self {
+            Slice(..) => "Slice",
+            Array(..) => "Array",
+            Ptr(..) => "Ptr",
+            Reference(..) => "Reference",
+            BareFn(..) => "BareFn",
+            Never(..) => "Never",
+            Tuple(..) => "Tuple",
+            Path(..) => "Path",
+            TraitObject(..) => "TraitObject",
+            ImplTrait(..) => "ImplTrait",
+            Paren(..) => "Paren",
+            Group(..) => "Group",
+            Infer(..) => "Infer",
+            Macro(..) => "Macro",
+            Verbatim(..) => "Verbatim",
+        }
+    }
+}
+
+impl Discrim for syn::Lit {
+    fn discrim(&self) -> &'static str {
+        use syn::Lit::*;
+        match self {
+            Str(..) => "Str",
+            ByteStr(..) => "ByteStr",
+            Byte(..) => "Byte",
+            Char(..) => "Char",
+            Int(..) => "Int",
+            Float(..) => "Float",
+            Bool(..) => "Bool",
+            Verbatim(..) => "Verbatim",
+        }
+    }
+}
+
+impl Discrim for proc_macro2::Delimiter {
+    fn discrim(&self) -> &'static str {
+        use proc_macro2::Delimiter::*;
+        match self {
+            Parenthesis => "Parenthesis",
+            Brace => "Brace",
+            Bracket => "Bracket",
+            None => "None",
+        }
+    }
+}
+
 impl Discrim for syn::Stmt {
     fn discrim(&self) -> &'static str {
         use syn::Stmt::*;
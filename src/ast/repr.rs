@@ -1,3 +1,4 @@
+use proc_macro2::TokenTree;
 use std::fmt::Display;
 use std::io::Write;
 
@@ -42,37 +43,37 @@ impl ReprEmitter {
 impl Emitter for ReprEmitter {
     fn meta(&mut self, x: u32) {
         self.maybe_comma();
-        write!(self.buf, "${}", x);
+        write!(self.buf, "${}", x).unwrap();
         self.sibling = true;
     }
 
     fn item(&mut self, s: impl Display) {
         self.maybe_comma();
-        write!(self.buf, "{}", s);
+        write!(self.buf, "{}", s).unwrap();
         self.sibling = true;
     }
 
     fn text_item(&mut self, s: impl Display) {
         self.maybe_comma();
-        write!(self.buf, "{}", s);
+        write!(self.buf, "{}", s).unwrap();
         self.sibling = true;
     }
 
     fn opener(&mut self, s: impl Display) {
         self.maybe_comma();
-        write!(self.buf, "{}{{", s);
+        write!(self.buf, "{}{{", s).unwrap();
         self.sibling = true;
     }
 
     fn closer(&mut self) {
         self.maybe_comma();
-        write!(self.buf, "}}");
+        write!(self.buf, "}}").unwrap();
         self.sibling = true;
     }
 
     fn maybe_break(&mut self) {
         if self.buf.position() != 0 {
-            writeln!(self.buf);
+            writeln!(self.buf).unwrap();
             self.sibling = false;
         }
     }
@@ -91,7 +92,7 @@ pub(crate) struct JsonEmitter {
 impl JsonEmitter {
     pub fn new() -> Self {
         let mut buf = std::io::Cursor::new(Vec::new());
-        write!(buf, "[");
+        write!(buf, "[").unwrap();
         let sibling = false;
         JsonEmitter {
             buf,
@@ -124,37 +125,37 @@ impl JsonEmitter {
 impl Emitter for JsonEmitter {
     fn finish(mut self) -> Vec<u8> {
         if !self.scalar_context {
-            write!(self.buf, "]");
+            write!(self.buf, "]").unwrap();
         }
         self.buf.into_inner()
     }
 
     fn meta(&mut self, x: u32) {
         self.maybe_comma();
-        write!(self.buf, "\"${}\"", x);
+        write!(self.buf, "\"${}\"", x).unwrap();
         self.sibling = true;
     }
 
     fn item(&mut self, s: impl Display) {
         self.maybe_comma();
-        write!(self.buf, "{}", s);
+        write!(self.buf, "{}", s).unwrap();
         self.sibling = true;
     }
 
     fn text_item(&mut self, s: impl Display) {
         self.maybe_comma();
-        write!(self.buf, "\"{}\"", s);
+        write!(self.buf, "\"{}\"", s).unwrap();
         self.sibling = true;
     }
 
     fn opener(&mut self, s: impl Display) {
         self.maybe_comma();
-        write!(self.buf, "[\"{}\"", s);
+        write!(self.buf, "[\"{}\"", s).unwrap();
         self.sibling = true;
     }
 
     fn closer(&mut self) {
-        write!(self.buf, "]");
+        write!(self.buf, "]").unwrap();
         self.sibling = true;
     }
 }
@@ -173,45 +174,83 @@ impl<'t, E: Emitter> ReprGenerator<'t, E> {
     }
 }
 
+impl<'t, E: Emitter> ReprGenerator<'t, E> {
+    /// If the pattern has a metavar here instead of a subtree, show the metavar.
+    fn open_bindable(&mut self) -> Result<(), ()> {
+        if let Err(()) = self.trace.open_subtree() {
+            match self.trace.consume_meta() {
+                WILDCARD => self.emitter.text_item("$_"),
+                x => self.emitter.meta(u32::from(x)),
+            }
+            return Err(());
+        }
+        Ok(())
+    }
+}
+
 impl<'t, 'ast, E: Emitter> Visitor<'ast> for ReprGenerator<'t, E> {
     type Output = String;
 
     fn open_expr(&mut self, x: &syn::Expr) -> Result<(), ()> {
-        if let Err(()) = self.trace.open_subtree() {
-            let x = u32::from(self.trace.consume_meta());
-            self.emitter.meta(x);
-            return Err(());
-        }
+        self.open_bindable()?;
         self.emitter.opener(x.discrim());
         Ok(())
     }
     fn open_ident(&mut self, x: &syn::Ident) -> Result<(), ()> {
-        if let Err(()) = self.trace.open_subtree() {
-            let x = u32::from(self.trace.consume_meta());
-            self.emitter.meta(x);
-            return Err(());
-        }
+        self.open_bindable()?;
         self.emitter.text_item(x);
         Ok(())
     }
-    fn open_stmt(&mut self, x: &syn::Stmt) {
-        self.open_subtree();
+    fn open_type(&mut self, x: &syn::Type) -> Result<(), ()> {
+        self.open_bindable()?;
+        self.emitter.opener(x.discrim());
+        Ok(())
+    }
+    fn open_stmt(&mut self, x: &syn::Stmt) -> Result<(), ()> {
         self.emitter.maybe_break();
+        self.open_bindable()?;
         self.emitter.opener(x.discrim());
+        Ok(())
     }
-    fn open_pat(&mut self, x: &syn::Pat) {
-        self.open_subtree();
+    fn open_pat(&mut self, x: &syn::Pat) -> Result<(), ()> {
+        self.open_bindable()?;
         self.emitter.opener(x.discrim());
+        Ok(())
+    }
+    fn open_path(&mut self, _: &syn::Path) -> Result<(), ()> {
+        // a path is traced as its own subtree, but shown inline with its parent
+        self.open_bindable()
     }
-    fn open_lit_int(&mut self, x: &syn::LitInt) {
-        self.open_datum();
-        self.emitter.item(x.value());
+    fn open_lifetime(&mut self, _: &syn::Lifetime) -> Result<(), ()> {
+        self.open_bindable()?;
+        self.emitter.opener("Lifetime");
+        Ok(())
+    }
+    fn open_block(&mut self, _: &syn::Block) -> Result<(), ()> {
+        self.open_bindable()?;
+        self.emitter.opener("Block");
+        Ok(())
+    }
+    fn open_tt(&mut self, x: &TokenTree) -> Result<(), ()> {
+        self.open_bindable()?;
+        tt_item(&mut self.emitter, x);
+        Ok(())
+    }
+    fn open_lit(&mut self, x: &syn::Lit) -> Result<(), ()> {
+        self.open_bindable()?;
+        self.emitter.opener(x.discrim());
+        self.emitter.item(lit_value(x));
+        Ok(())
     }
 
     fn close_expr(&mut self, _: &syn::Expr) {
         self.close_subtree();
         self.emitter.closer();
     }
+    fn close_type(&mut self, _: &syn::Type) {
+        self.close_subtree();
+        self.emitter.closer();
+    }
     fn close_stmt(&mut self, _: &syn::Stmt) {
         self.close_subtree();
         self.emitter.closer();
@@ -220,6 +259,24 @@ impl<'t, 'ast, E: Emitter> Visitor<'ast> for ReprGenerator<'t, E> {
         self.close_subtree();
         self.emitter.closer();
     }
+    fn close_lifetime(&mut self, _: &syn::Lifetime) {
+        self.close_subtree();
+        self.emitter.closer();
+    }
+    fn close_block(&mut self, _: &syn::Block) {
+        self.close_subtree();
+        self.emitter.closer();
+    }
+    fn close_tt(&mut self, x: &TokenTree) {
+        self.close_subtree();
+        if let TokenTree::Group(_) = x {
+            self.emitter.closer();
+        }
+    }
+    fn close_lit(&mut self, _: &syn::Lit) {
+        self.close_subtree();
+        self.emitter.closer();
+    }
 
     fn open_subtree(&mut self) {
         self.trace.open_subtree().unwrap();
@@ -267,39 +324,100 @@ impl<E: Emitter> Visitor<'_> for PlainAstRepr<E> {
         self.emitter.text_item(x);
         Ok(())
     }
-    fn open_stmt(&mut self, x: &syn::Stmt) {
+    fn open_type(&mut self, x: &syn::Type) -> Result<(), ()> {
+        self.emitter.opener(x.discrim());
+        Ok(())
+    }
+    fn open_stmt(&mut self, x: &syn::Stmt) -> Result<(), ()> {
         self.emitter.maybe_break();
         self.emitter.opener(x.discrim());
+        Ok(())
     }
-    fn open_pat(&mut self, x: &syn::Pat) {
+    fn open_pat(&mut self, x: &syn::Pat) -> Result<(), ()> {
         self.emitter.opener(x.discrim());
+        Ok(())
+    }
+    fn open_lifetime(&mut self, _: &syn::Lifetime) -> Result<(), ()> {
+        self.emitter.opener("Lifetime");
+        Ok(())
     }
-    fn open_lit_int(&mut self, x: &syn::LitInt) {
-        self.emitter.item(x.value());
+    fn open_block(&mut self, _: &syn::Block) -> Result<(), ()> {
+        self.emitter.opener("Block");
+        Ok(())
+    }
+    fn open_tt(&mut self, x: &TokenTree) -> Result<(), ()> {
+        tt_item(&mut self.emitter, x);
+        Ok(())
+    }
+    fn open_lit(&mut self, x: &syn::Lit) -> Result<(), ()> {
+        self.emitter.opener(x.discrim());
+        self.emitter.item(lit_value(x));
+        Ok(())
     }
 
     fn close_expr(&mut self, _: &syn::Expr) {
         self.emitter.closer();
     }
+    fn close_type(&mut self, _: &syn::Type) {
+        self.emitter.closer();
+    }
     fn close_stmt(&mut self, _: &syn::Stmt) {
         self.emitter.closer();
     }
     fn close_pat(&mut self, _: &syn::Pat) {
         self.emitter.closer();
     }
+    fn close_lifetime(&mut self, _: &syn::Lifetime) {
+        self.emitter.closer();
+    }
+    fn close_block(&mut self, _: &syn::Block) {
+        self.emitter.closer();
+    }
+    fn close_tt(&mut self, x: &TokenTree) {
+        if let TokenTree::Group(_) = x {
+            self.emitter.closer();
+        }
+    }
+    fn close_lit(&mut self, _: &syn::Lit) {
+        self.emitter.closer();
+    }
 
     fn open_subtree(&mut self) {}
     fn close_subtree(&mut self) {}
     fn open_datum(&mut self) {}
     fn close_datum(&mut self) {}
-    fn push_byte(&mut self, x: u8) {}
-    fn extend_bytes(&mut self, x: &[u8]) {}
+    fn push_byte(&mut self, _: u8) {}
+    fn extend_bytes(&mut self, _: &[u8]) {}
 
     fn finish(self) -> Self::Output {
         String::from_utf8(self.emitter.finish()).unwrap()
     }
 }
 
+/// Show a token tree; a group is left open for its contents.
+fn tt_item(emitter: &mut impl Emitter, x: &TokenTree) {
+    match x {
+        TokenTree::Group(x) => emitter.opener(x.delimiter().discrim()),
+        TokenTree::Ident(x) => emitter.text_item(x),
+        TokenTree::Punct(x) => emitter.text_item(x.as_char()),
+        TokenTree::Literal(x) => emitter.item(format!("{:?}", x.to_string())),
+    }
+}
+
+/// A literal's value, as a JSON scalar.
+fn lit_value(x: &syn::Lit) -> String {
+    match x {
+        syn::Lit::Str(x) => format!("{:?}", x.value()),
+        syn::Lit::ByteStr(x) => format!("{:?}", String::from_utf8_lossy(&x.value())),
+        syn::Lit::Byte(x) => x.value().to_string(),
+        syn::Lit::Char(x) => format!("{:?}", x.value().to_string()),
+        syn::Lit::Int(x) => x.value().to_string(),
+        syn::Lit::Float(x) => x.value().to_string(),
+        syn::Lit::Bool(x) => x.value.to_string(),
+        syn::Lit::Verbatim(x) => format!("{:?}", x.token.to_string()),
+    }
+}
+
 pub fn pattern_json<'a, 'v: 'a, V>(trace: &Trace, v: &'v V) -> String where V: Visitable<'a>+?Sized {
     ReprGenerator::new(trace, JsonEmitter::new()).visit(v)
 }
@@ -312,20 +430,37 @@ pub fn input_json<'a, 'v: 'a, V>(v: &'v V) -> String where V: Visitable<'a>+?Siz
     PlainAstRepr::new(JsonEmitter::new_scalar()).visit(v)
 }
 
-pub fn bindings_json(bindings: &'_ Bindings) -> String {
-    let f = |b: &Binding| match b {
+fn binding_json(b: &Binding) -> String {
+    match b {
         Binding::Ident(i) => format!("[\"Ident\",\"{}\"]", i),
-        Binding::Expr(x) => format!("[\"Expr\",{}]", input_json(*x))
-    };
+        Binding::Expr(x) => format!("[\"Expr\",{}]", input_json(*x)),
+        Binding::Type(x) => format!("[\"Type\",{}]", input_json(*x)),
+        Binding::Pat(x) => format!("[\"Pat\",{}]", input_json(*x)),
+        Binding::Lit(x) => format!("[\"Lit\",{}]", input_json(*x)),
+        Binding::Stmt(x) => format!("[\"Stmt\",{}]", input_json(*x)),
+        Binding::Block(x) => format!("[\"Block\",{}]", input_json(*x)),
+        Binding::Path(x) => format!("[\"Path\",{}]", input_json(*x)),
+        Binding::Lifetime(x) => format!("[\"Lifetime\",\"{}\"]", x),
+        Binding::Tt(x) => format!("[\"Tt\",{}]", input_json(x)),
+        Binding::Repeat(xs) => format!("[\"Repeat\",{}]", binding_list_json(xs)),
+        Binding::Absent => "[\"Absent\"]".to_owned(),
+    }
+}
+
+fn binding_list_json(binds: &[Binding]) -> String {
     let mut buf = "[".to_owned();
-    let mut binds = bindings.binds.iter();
+    let mut binds = binds.iter();
     if let Some(b) = binds.next() {
-        buf.push_str(&f(b));
+        buf.push_str(&binding_json(b));
     }
     for b in binds {
-        buf.push_str(",");
-        buf.push_str(&f(b));
+        buf.push(',');
+        buf.push_str(&binding_json(b));
     }
-    buf.push_str("]");
+    buf.push(']');
     buf
 }
+
+pub fn bindings_json(bindings: &'_ Bindings) -> String {
+    binding_list_json(&bindings.binds)
+}
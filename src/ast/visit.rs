@@ -1,5 +1,13 @@
+use proc_macro2::{TokenStream, TokenTree};
+use std::mem::{discriminant, transmute, Discriminant};
 use syn::visit::Visit;
 
+// Kinds of token tree that aren't an ident or a group, chosen to be distinct from the Delimiter of a
+// group and from the first byte of an ident (so that idents in macro invocations are traced just
+// like other idents).
+const TT_PUNCT: u8 = 4;
+const TT_LITERAL: u8 = 5;
+
 pub trait Visitor<'ast> {
     type Output;
 
@@ -13,15 +21,45 @@ pub trait Visitor<'ast> {
         self.open_subtree();
         Ok(())
     }
-    fn open_stmt(&mut self, _: &'ast syn::Stmt) {
-        self.open_subtree()
+    fn open_type(&mut self, _: &'ast syn::Type) -> Result<(), ()> {
+        self.open_subtree();
+        Ok(())
+    }
+    fn open_stmt(&mut self, _: &'ast syn::Stmt) -> Result<(), ()> {
+        self.open_subtree();
+        Ok(())
+    }
+    fn open_pat(&mut self, _: &'ast syn::Pat) -> Result<(), ()> {
+        self.open_subtree();
+        Ok(())
+    }
+    fn open_path(&mut self, _: &'ast syn::Path) -> Result<(), ()> {
+        self.open_subtree();
+        Ok(())
+    }
+    fn open_lifetime(&mut self, _: &'ast syn::Lifetime) -> Result<(), ()> {
+        self.open_subtree();
+        Ok(())
+    }
+    fn open_block(&mut self, _: &'ast syn::Block) -> Result<(), ()> {
+        self.open_subtree();
+        Ok(())
+    }
+    fn open_tt(&mut self, _: &TokenTree) -> Result<(), ()> {
+        self.open_subtree();
+        Ok(())
     }
-    fn open_pat(&mut self, _: &'ast syn::Pat) {
-        self.open_subtree()
+    fn open_lit(&mut self, _: &'ast syn::Lit) -> Result<(), ()> {
+        self.open_subtree();
+        Ok(())
     }
     fn open_lit_int(&mut self, _: &'ast syn::LitInt) {
         self.open_datum()
     }
+    fn open_field_value(&mut self, _: &'ast syn::FieldValue) -> Result<(), ()> {
+        self.open_subtree();
+        Ok(())
+    }
 
     fn close_expr(&mut self, _: &'ast syn::Expr) {
         self.close_subtree()
@@ -29,15 +67,36 @@ pub trait Visitor<'ast> {
     fn close_ident(&mut self, _: &'ast syn::Ident) {
         self.close_subtree()
     }
+    fn close_type(&mut self, _: &'ast syn::Type) {
+        self.close_subtree()
+    }
     fn close_stmt(&mut self, _: &'ast syn::Stmt) {
         self.close_subtree()
     }
     fn close_pat(&mut self, _: &'ast syn::Pat) {
         self.close_subtree()
     }
+    fn close_path(&mut self, _: &'ast syn::Path) {
+        self.close_subtree()
+    }
+    fn close_lifetime(&mut self, _: &'ast syn::Lifetime) {
+        self.close_subtree()
+    }
+    fn close_block(&mut self, _: &'ast syn::Block) {
+        self.close_subtree()
+    }
+    fn close_tt(&mut self, _: &TokenTree) {
+        self.close_subtree()
+    }
+    fn close_lit(&mut self, _: &'ast syn::Lit) {
+        self.close_subtree()
+    }
     fn close_lit_int(&mut self, _: &'ast syn::LitInt) {
         self.close_datum()
     }
+    fn close_field_value(&mut self, _: &'ast syn::FieldValue) {
+        self.close_subtree()
+    }
 
     fn open_subtree(&mut self);
     fn close_subtree(&mut self);
@@ -69,6 +128,62 @@ impl<'a> Visitable<'a> for syn::Expr {
     }
 }
 
+impl<'a> Visitable<'a> for syn::Type {
+    fn apply<'v, V: Visitor<'a>>(&'a self, v: &'v mut V) {
+        let mut v = SynVis { inner: v };
+        v.visit_type(self);
+    }
+}
+
+impl<'a> Visitable<'a> for syn::Pat {
+    fn apply<'v, V: Visitor<'a>>(&'a self, v: &'v mut V) {
+        let mut v = SynVis { inner: v };
+        v.visit_pat(self);
+    }
+}
+
+impl<'a> Visitable<'a> for syn::Arm {
+    fn apply<'v, V: Visitor<'a>>(&'a self, v: &'v mut V) {
+        let mut v = SynVis { inner: v };
+        v.visit_arm(self);
+    }
+}
+
+impl<'a> Visitable<'a> for syn::Lit {
+    fn apply<'v, V: Visitor<'a>>(&'a self, v: &'v mut V) {
+        let mut v = SynVis { inner: v };
+        v.visit_lit(self);
+    }
+}
+
+impl<'a> Visitable<'a> for syn::Path {
+    fn apply<'v, V: Visitor<'a>>(&'a self, v: &'v mut V) {
+        let mut v = SynVis { inner: v };
+        v.visit_path(self);
+    }
+}
+
+impl<'a> Visitable<'a> for syn::Lifetime {
+    fn apply<'v, V: Visitor<'a>>(&'a self, v: &'v mut V) {
+        let mut v = SynVis { inner: v };
+        v.visit_lifetime(self);
+    }
+}
+
+impl<'a> Visitable<'a> for syn::Block {
+    fn apply<'v, V: Visitor<'a>>(&'a self, v: &'v mut V) {
+        let mut v = SynVis { inner: v };
+        v.visit_block(self);
+    }
+}
+
+impl<'a> Visitable<'a> for TokenTree {
+    fn apply<'v, V: Visitor<'a>>(&'a self, v: &'v mut V) {
+        let mut v = SynVis { inner: v };
+        v.visit_token_tree(self);
+    }
+}
+
 impl<'a> Visitable<'a> for syn::Stmt {
     fn apply<'v, V: Visitor<'a>>(&'a self, v: &'v mut V) {
         let mut v = SynVis { inner: v };
@@ -99,11 +214,53 @@ struct SynVis<'v, V> {
     inner: &'v mut V,
 }
 
+/// The byte that identifies a literal's kind in a trace.
+pub(crate) fn lit_discrim(x: &syn::Lit) -> u8 {
+    let discrim = unsafe { transmute::<Discriminant<syn::Lit>, u64>(discriminant(x)) };
+    discrim as u8
+}
+
+impl<'ast, 'v, V: Visitor<'ast>> SynVis<'v, V> {
+    /// Trace the unparsed tokens of a macro invocation, each token tree as a subtree. A `tt`
+    /// metavar stands for one of those subtrees, so it matches exactly one token tree: a macro
+    /// argument of several tokens, like `x.len()`, can't be bound by a single `$a: tt`.
+    fn visit_token_stream(&mut self, x: &TokenStream) {
+        for tt in x.clone() {
+            self.visit_token_tree(&tt);
+        }
+    }
+
+    fn visit_token_tree(&mut self, x: &TokenTree) {
+        if let Err(()) = self.inner.open_tt(x) {
+            return;
+        }
+        match x {
+            TokenTree::Group(x) => {
+                self.inner.push_byte(x.delimiter() as u8);
+                self.visit_token_stream(&x.stream());
+            }
+            TokenTree::Ident(x) => self.inner.extend_bytes(x.to_string().as_bytes()),
+            TokenTree::Punct(x) => {
+                self.inner.push_byte(TT_PUNCT);
+                self.inner.extend_bytes(x.as_char().to_string().as_bytes());
+                self.inner.push_byte(x.spacing() as u8);
+            }
+            TokenTree::Literal(x) => {
+                self.inner.push_byte(TT_LITERAL);
+                self.inner.extend_bytes(x.to_string().as_bytes());
+            }
+        }
+        self.inner.close_tt(x);
+    }
+}
+
 impl<'ast, 'v, V: Visitor<'ast>> Visit<'ast> for SynVis<'v, V> {
     fn visit_stmt(&mut self, x: &'ast syn::Stmt) {
-        self.inner.open_stmt(x);
-        self.inner
-            .push_byte(unsafe { std::mem::transmute::<_, u64>(std::mem::discriminant(x)) } as u8);
+        if let Err(()) = self.inner.open_stmt(x) {
+            return;
+        }
+        let discrim = unsafe { transmute::<Discriminant<syn::Stmt>, u64>(discriminant(x)) };
+        self.inner.push_byte(discrim as u8);
         syn::visit::visit_stmt(self, x);
         self.inner.close_stmt(x);
     }
@@ -112,14 +269,28 @@ impl<'ast, 'v, V: Visitor<'ast>> Visit<'ast> for SynVis<'v, V> {
         if let Err(()) = self.inner.open_expr(x) {
             return;
         }
-        self.inner
-            .push_byte(unsafe { std::mem::transmute::<_, u64>(std::mem::discriminant(x)) } as u8);
+        let discrim = unsafe { transmute::<Discriminant<syn::Expr>, u64>(discriminant(x)) };
+        self.inner.push_byte(discrim as u8);
         syn::visit::visit_expr(self, x);
         self.inner.close_expr(x);
     }
 
+    fn visit_type(&mut self, x: &'ast syn::Type) {
+        if let Err(()) = self.inner.open_type(x) {
+            return;
+        }
+        let discrim = unsafe { transmute::<Discriminant<syn::Type>, u64>(discriminant(x)) };
+        self.inner.push_byte(discrim as u8);
+        syn::visit::visit_type(self, x);
+        self.inner.close_type(x);
+    }
+
     fn visit_pat(&mut self, x: &'ast syn::Pat) {
-        self.inner.open_pat(x);
+        if let Err(()) = self.inner.open_pat(x) {
+            return;
+        }
+        let discrim = unsafe { transmute::<Discriminant<syn::Pat>, u64>(discriminant(x)) };
+        self.inner.push_byte(discrim as u8);
         syn::visit::visit_pat(self, x);
         self.inner.close_pat(x);
     }
@@ -132,10 +303,136 @@ impl<'ast, 'v, V: Visitor<'ast>> Visit<'ast> for SynVis<'v, V> {
         self.inner.close_ident(x);
     }
 
+    fn visit_path(&mut self, x: &'ast syn::Path) {
+        if let Err(()) = self.inner.open_path(x) {
+            return;
+        }
+        self.inner.push_byte(x.leading_colon.is_some() as u8);
+        syn::visit::visit_path(self, x);
+        self.inner.close_path(x);
+    }
+
+    fn visit_lifetime(&mut self, x: &'ast syn::Lifetime) {
+        if let Err(()) = self.inner.open_lifetime(x) {
+            return;
+        }
+        syn::visit::visit_lifetime(self, x);
+        self.inner.close_lifetime(x);
+    }
+
+    fn visit_bin_op(&mut self, x: &'ast syn::BinOp) {
+        let discrim = unsafe { transmute::<Discriminant<syn::BinOp>, u64>(discriminant(x)) };
+        self.inner.push_byte(discrim as u8);
+    }
+
+    fn visit_un_op(&mut self, x: &'ast syn::UnOp) {
+        let discrim = unsafe { transmute::<Discriminant<syn::UnOp>, u64>(discriminant(x)) };
+        self.inner.push_byte(discrim as u8);
+    }
+
+    fn visit_local(&mut self, x: &'ast syn::Local) {
+        for it in &x.attrs {
+            self.visit_attribute(it);
+        }
+        for it in &x.pats {
+            self.visit_pat(it);
+        }
+        // each optional child gets a subtree whether it's present or not, so that the children
+        // that are present can't be mistaken for one another
+        self.inner.open_subtree();
+        if let Some((_, ty)) = &x.ty {
+            self.visit_type(ty);
+        }
+        self.inner.close_subtree();
+        self.inner.open_subtree();
+        if let Some((_, init)) = &x.init {
+            self.visit_expr(init);
+        }
+        self.inner.close_subtree();
+    }
+
+    fn visit_arm(&mut self, x: &'ast syn::Arm) {
+        // a subtree per arm, so that an arm can't be mistaken for other siblings; and one for the
+        // guard whether it's present or not
+        self.inner.open_subtree();
+        for it in &x.attrs {
+            self.visit_attribute(it);
+        }
+        for it in &x.pats {
+            self.visit_pat(it);
+        }
+        self.inner.open_subtree();
+        if let Some((_, guard)) = &x.guard {
+            self.visit_expr(guard);
+        }
+        self.inner.close_subtree();
+        self.visit_expr(&x.body);
+        self.inner.close_subtree();
+    }
+
+    fn visit_field_value(&mut self, x: &'ast syn::FieldValue) {
+        // a subtree per field, so that fields can be matched in any order
+        if let Err(()) = self.inner.open_field_value(x) {
+            return;
+        }
+        syn::visit::visit_field_value(self, x);
+        self.inner.close_field_value(x);
+    }
+
+    fn visit_macro(&mut self, x: &'ast syn::Macro) {
+        syn::visit::visit_macro(self, x);
+        self.visit_token_stream(&x.tts);
+    }
+
+    fn visit_block(&mut self, x: &'ast syn::Block) {
+        if let Err(()) = self.inner.open_block(x) {
+            return;
+        }
+        syn::visit::visit_block(self, x);
+        self.inner.close_block(x);
+    }
+
+    fn visit_lit(&mut self, x: &'ast syn::Lit) {
+        if let Err(()) = self.inner.open_lit(x) {
+            return;
+        }
+        self.inner.push_byte(lit_discrim(x));
+        syn::visit::visit_lit(self, x);
+        self.inner.close_lit(x);
+    }
+
+    fn visit_lit_str(&mut self, x: &'ast syn::LitStr) {
+        self.inner.extend_bytes(x.value().as_bytes());
+    }
+
+    fn visit_lit_byte_str(&mut self, x: &'ast syn::LitByteStr) {
+        self.inner.extend_bytes(&x.value());
+    }
+
+    fn visit_lit_byte(&mut self, x: &'ast syn::LitByte) {
+        self.inner.push_byte(x.value());
+    }
+
+    fn visit_lit_char(&mut self, x: &'ast syn::LitChar) {
+        self.inner.extend_bytes(x.value().to_string().as_bytes());
+    }
+
     fn visit_lit_int(&mut self, x: &'ast syn::LitInt) {
         self.inner.open_lit_int(x);
         // TODO: compact repr?
         self.inner.extend_bytes(format!("{}", x.value()).as_bytes());
         self.inner.close_lit_int(x);
     }
+
+    fn visit_lit_float(&mut self, x: &'ast syn::LitFloat) {
+        self.inner.extend_bytes(&x.value().to_bits().to_le_bytes());
+    }
+
+    fn visit_lit_bool(&mut self, x: &'ast syn::LitBool) {
+        self.inner.push_byte(x.value as u8);
+    }
+
+    fn visit_lit_verbatim(&mut self, x: &'ast syn::LitVerbatim) {
+        self.inner.extend_bytes(x.token.to_string().as_bytes());
+    }
 }
@@ -6,8 +6,13 @@
 /// This module supports using (2) + (3) to produce (4).
 use proc_macro2::{TokenStream, TokenTree};
 
+use crate::ast::{lit_discrim, Context, Error, Inside, Result};
+use crate::trace::{Constraint, Scope, UseCount, Value, ValueTest, MVAR_LIMIT, WILDCARD};
 use log::trace;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::iter::Peekable;
 
 type DefMap = HashMap<String, MetaDef>;
 
@@ -18,44 +23,275 @@ enum MacBodyState {
     Cont,
 }
 
-struct MacBodyTransducer<'a, It, F> {
-    ts: It,
+/// A `$( ... )` group in a macro body.
+#[derive(Debug)]
+pub enum Group {
+    /// `$( ... ) sep op`, where `op` is one of `*`, `+`, `?`.
+    Repetition {
+        op: char,
+        separator: Option<TokenTree>,
+    },
+    /// `$( ... | ... )`: any one of the branches.
+    Alternation { branches: usize },
+    /// `...` between statements, or `..` among arguments: any number of them.
+    Gap,
+    /// `{ ..., .. }` in a struct literal: the fields in any order, among any others.
+    Fields,
+    /// `. ... .` in a method chain: any number of method calls.
+    Chain,
+}
+
+/// The groups of a macro body, shared by the transducers of its nested token groups.
+struct Groups {
+    // a group to expand differently: to the given number of copies of a repetition, or to the
+    // given branch of an alternation. Every other group is expanded to one copy or first branch.
+    expanded: Option<(usize, usize)>,
+    // trace the expanded group's metavars as wildcards, to tell its copy from identical siblings
+    marked: bool,
+    seen: RefCell<Vec<Group>>,
+    // set when the body turns out not to be valid pattern syntax
+    failed: Cell<bool>,
+    // each use of a metavar of inferred kind, and whether it is in a pattern
+    inferred: RefCell<Vec<(u32, bool)>>,
+}
+
+impl Groups {
+    fn new(expanded: Option<(usize, usize)>, marked: bool) -> Self {
+        Groups {
+            expanded,
+            marked,
+            seen: RefCell::new(Vec::new()),
+            failed: Cell::new(false),
+            inferred: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+fn repeat_op(tt: &TokenTree) -> Option<char> {
+    match tt {
+        TokenTree::Punct(c) if ['*', '+', '?'].contains(&c.as_char()) => Some(c.as_char()),
+        _ => None,
+    }
+}
+
+struct MacBodyTransducer<'a, It: Iterator, F> {
+    ts: Peekable<It>,
     defs: &'a DefMap,
     state: MacBodyState,
     cont: Vec<TokenTree>,
     tokenize: &'a F,
+    groups: &'a Groups,
+    in_group: bool,
+    // in the copy of a marked group
+    marked: bool,
+    // not within any delimited group; where a `...` gap can be
+    toplevel: bool,
+    // the last token emitted at this level
+    prev: Option<TokenTree>,
+    // reading a pattern: after `let` or `for`, or a match arm's pattern
+    pattern: bool,
+    // reading match arms
+    arms: bool,
+    // after `=>`, before the arm's body
+    arm_body: bool,
+    // after `match`, before its arms
+    scrutinee: bool,
 }
 
-impl<'a, It, F> MacBodyTransducer<'a, It, F> {
-    fn new(ts: It, defs: &'a DefMap, tokenize: &'a F) -> Self {
+impl<'a, It: Iterator<Item = TokenTree>, F> MacBodyTransducer<'a, It, F> {
+    fn new(ts: It, defs: &'a DefMap, tokenize: &'a F, groups: &'a Groups) -> Self {
         let state = MacBodyState::AwaitingDollar;
         let cont = Vec::new();
         MacBodyTransducer {
-            ts,
+            ts: ts.peekable(),
             defs,
             state,
             cont,
             tokenize,
+            groups,
+            in_group: false,
+            marked: false,
+            toplevel: true,
+            prev: None,
+            pattern: false,
+            arms: false,
+            arm_body: false,
+            scrutinee: false,
+        }
+    }
+
+    /// A transducer for the contents of a group.
+    fn nested<Ts: Iterator<Item = TokenTree>>(&self, ts: Ts) -> MacBodyTransducer<'a, Ts, F> {
+        let mut nested = MacBodyTransducer::new(ts, self.defs, self.tokenize, self.groups);
+        nested.in_group = self.in_group;
+        nested.marked = self.marked;
+        nested.toplevel = false;
+        nested.pattern = self.pattern;
+        nested.arms = self.arms;
+        nested
+    }
+
+    /// A transducer for the contents of a delimited group that has just been read.
+    fn delimited<Ts: Iterator<Item = TokenTree>>(&self, ts: Ts) -> MacBodyTransducer<'a, Ts, F> {
+        let mut nested = self.nested(ts);
+        nested.arms = self.scrutinee;
+        nested.pattern = self.pattern || self.scrutinee;
+        nested
+    }
+
+    /// Follow the syntax far enough to tell patterns from expressions.
+    fn observe(&mut self, tt: &TokenTree) {
+        use proc_macro2::{Delimiter, Spacing};
+        let joint = |tt: &Option<TokenTree>, c| match tt {
+            Some(TokenTree::Punct(p)) => p.as_char() == c && p.spacing() == Spacing::Joint,
+            _ => false,
+        };
+        let arm_body = std::mem::replace(&mut self.arm_body, false);
+        match tt {
+            TokenTree::Ident(id) if id == "let" || id == "for" => self.pattern = true,
+            TokenTree::Ident(id) if id == "in" || id == "if" => self.pattern = false,
+            TokenTree::Ident(id) if id == "match" => self.scrutinee = true,
+            TokenTree::Group(g) if g.delimiter() == Delimiter::Brace => {
+                self.scrutinee = false;
+                if arm_body {
+                    self.pattern = true;
+                }
+            }
+            TokenTree::Punct(p) if p.as_char() == '>' && joint(&self.prev, '=') => {
+                self.pattern = false;
+                self.arm_body = self.arms;
+            }
+            // `=`, not the end of `==` or `<=`
+            TokenTree::Punct(p)
+                if p.as_char() == '='
+                    && p.spacing() == Spacing::Alone
+                    && !matches!(self.prev, Some(TokenTree::Punct(ref q)) if q.spacing() == Spacing::Joint) =>
+            {
+                self.pattern = false
+            }
+            TokenTree::Punct(p) if p.as_char() == ';' => self.pattern = false,
+            TokenTree::Punct(p) if p.as_char() == ',' && self.arms && !arm_body => {
+                self.pattern = true;
+            }
+            _ => (),
+        }
+        self.prev = Some(tt.clone());
+    }
+
+    /// Whether the last token emitted can end the callee of a call or the path of a struct
+    /// literal.
+    fn after_path(&self) -> bool {
+        const KEYWORDS: &[&str] = &[
+            "if", "while", "match", "return", "in", "break", "let", "for", "loop", "move", "else",
+            "mut", "ref", "box", "unsafe",
+        ];
+        match &self.prev {
+            Some(TokenTree::Ident(id)) => !KEYWORDS.contains(&id.to_string().as_str()),
+            Some(TokenTree::Punct(p)) => p.as_char() == '>',
+            _ => false,
         }
     }
+
+    /// Note that the body is malformed, and stop reading it.
+    fn fail(&self) {
+        self.groups.failed.set(true);
+    }
+
+    /// After a metavar, consume a `:kind` annotation if there is one, with its `?` and `~ "regex"`
+    /// suffixes. A `:` that isn't followed by a kind is left to be emitted after the placeholder.
+    fn annotation(&mut self) -> Option<(NodeType, bool, Option<String>)> {
+        use proc_macro2::TokenTree::*;
+        match self.ts.peek() {
+            Some(Punct(ref c)) if c.as_char() == ':' => (),
+            _ => return None,
+        }
+        let colon = self.ts.next().unwrap();
+        if let Some(Ident(kind)) = self.ts.peek() {
+            if let Some(node) = NodeType::from_name(&kind.to_string()) {
+                self.ts.next();
+                let optional = match self.ts.peek() {
+                    Some(Punct(ref c)) if c.as_char() == '?' => self.ts.next().is_some(),
+                    _ => false,
+                };
+                let name = match self.ts.peek() {
+                    Some(Punct(ref c)) if c.as_char() == '~' => {
+                        self.ts.next();
+                        match name_regex(&node, self.ts.next()) {
+                            Ok(name) => Some(name),
+                            Err(Error) => {
+                                self.fail();
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                return Some((node, optional, name));
+            }
+        }
+        self.cont.push(colon);
+        None
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum NodeType {
     Expr,
     Ident,
+    Type,
+    Pat,
+    Lit,
+    Stmt,
+    Block,
+    Path,
+    Lifetime,
+    Tt,
+    /// Not declarable; the kind is determined by where the placeholder parses.
+    Infer,
+}
+
+impl NodeType {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "ident" => self::NodeType::Ident,
+            "expr" => self::NodeType::Expr,
+            "ty" => self::NodeType::Type,
+            "pat" => self::NodeType::Pat,
+            "lit" => self::NodeType::Lit,
+            "stmt" => self::NodeType::Stmt,
+            "block" => self::NodeType::Block,
+            "path" => self::NodeType::Path,
+            "lifetime" => self::NodeType::Lifetime,
+            "tt" => self::NodeType::Tt,
+            _ => return None,
+        })
+    }
 }
 
 #[derive(Debug)]
 struct MetaDef {
     node: NodeType,
     id: u32,
+    /// Declared with a `?` after its kind: it may also match where its syntax is absent.
+    optional: bool,
+    /// Declared with a `~ "regex"` after its kind: an ident must have a matching name.
+    name: Option<String>,
 }
 
 impl MetaDef {
+    /// The id of the metavar in traces. There can only be so many.
+    fn mvar(&self) -> Result<u8> {
+        let id = u8::try_from(self.id).map_err(|_| Error)?;
+        if id >= MVAR_LIMIT {
+            return Err(Error);
+        }
+        Ok(id)
+    }
+
     // placeholder token that must:
     // - parse as the right syntactic type
     // - always differ from the corresponding id_token
+    // (a lifetime placeholder is the ident following the tick, see MacBodyTransducer)
     fn node_token(&self) -> TokenTree {
         match self.node {
             self::NodeType::Ident => {
@@ -64,6 +300,31 @@ impl MetaDef {
             self::NodeType::Expr => {
                 syn::Ident::new("__EXPR", proc_macro2::Span::call_site()).into()
             }
+            self::NodeType::Type => {
+                syn::Ident::new("__TYPE", proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Pat => {
+                syn::Ident::new("__PAT", proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Lit => proc_macro2::Literal::string("__LIT").into(),
+            self::NodeType::Stmt => {
+                syn::Ident::new("__STMT", proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Block => {
+                let body = syn::Ident::new("__BLOCK", proc_macro2::Span::call_site());
+                let body = std::iter::once(TokenTree::from(body)).collect();
+                proc_macro2::Group::new(proc_macro2::Delimiter::Brace, body).into()
+            }
+            self::NodeType::Path => {
+                syn::Ident::new("__PATH", proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Lifetime => {
+                syn::Ident::new("__LIFETIME", proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Tt => syn::Ident::new("__TT", proc_macro2::Span::call_site()).into(),
+            self::NodeType::Infer => {
+                syn::Ident::new("__INFER", proc_macro2::Span::call_site()).into()
+            }
         }
     }
 
@@ -81,6 +342,35 @@ impl MetaDef {
             self::NodeType::Expr => {
                 syn::Ident::new(&format!("EXPR_{}", self.id), proc_macro2::Span::call_site()).into()
             }
+            self::NodeType::Type => {
+                syn::Ident::new(&format!("TYPE_{}", self.id), proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Pat => {
+                syn::Ident::new(&format!("PAT_{}", self.id), proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Lit => proc_macro2::Literal::string(&format!("LIT_{}", self.id)).into(),
+            self::NodeType::Stmt => {
+                syn::Ident::new(&format!("STMT_{}", self.id), proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Block => {
+                let body =
+                    syn::Ident::new(&format!("BLOCK_{}", self.id), proc_macro2::Span::call_site());
+                let body = std::iter::once(TokenTree::from(body)).collect();
+                proc_macro2::Group::new(proc_macro2::Delimiter::Brace, body).into()
+            }
+            self::NodeType::Path => {
+                syn::Ident::new(&format!("PATH_{}", self.id), proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Lifetime => syn::Ident::new(
+                &format!("LIFETIME_{}", self.id),
+                proc_macro2::Span::call_site(),
+            ).into(),
+            self::NodeType::Tt => {
+                syn::Ident::new(&format!("TT_{}", self.id), proc_macro2::Span::call_site()).into()
+            }
+            self::NodeType::Infer => {
+                syn::Ident::new(&format!("INFER_{}", self.id), proc_macro2::Span::call_site()).into()
+            }
         }
     }
 }
@@ -91,6 +381,16 @@ impl<'a, It: Iterator<Item = TokenTree>, F: Fn(&MetaDef) -> TokenTree> Iterator
     type Item = TokenTree;
 
     fn next(&mut self) -> Option<TokenTree> {
+        let tt = self.step();
+        if let Some(tt) = &tt {
+            self.observe(tt);
+        }
+        tt
+    }
+}
+
+impl<'a, It: Iterator<Item = TokenTree>, F: Fn(&MetaDef) -> TokenTree> MacBodyTransducer<'a, It, F> {
+    fn step(&mut self) -> Option<TokenTree> {
         use self::MacBodyState::*;
         use proc_macro2::TokenTree::*;
         if let Cont = self.state {
@@ -104,36 +404,551 @@ impl<'a, It: Iterator<Item = TokenTree>, F: Fn(&MetaDef) -> TokenTree> Iterator
         match (&self.state, tt) {
             (AwaitingDollar, Some(Punct(ref c))) if c.as_char() == '$' => {
                 self.state = AwaitingIdent;
-                self.next()
+                self.step()
             }
             (AwaitingDollar, Some(Group(ref g))) => {
                 let delim = g.delimiter();
-                let ts = MacBodyTransducer::new(g.stream().into_iter(), self.defs, self.tokenize)
-                    .collect();
+                let tts = self.list_gaps(g);
+                let ts = self.delimited(tts.into_iter()).collect();
                 Some(proc_macro2::Group::new(delim, ts).into())
             }
+            (AwaitingDollar, Some(Punct(c))) if c.as_char() == '.' => {
+                // `...` at the top level is a gap; `. ... .` anywhere is a chain gap
+                let mut dots = vec![Punct(c)];
+                while dots.len() < 5 {
+                    match self.ts.peek() {
+                        Some(Punct(ref d)) if d.as_char() == '.' => dots.push(self.ts.next().unwrap()),
+                        _ => break,
+                    }
+                }
+                self.cont = match dots.len() {
+                    5 => self.chain_gap(),
+                    3 if self.toplevel => self.gap(),
+                    _ => dots,
+                };
+                self.cont.reverse();
+                self.state = Cont;
+                self.step()
+            }
             (AwaitingDollar, x) => x,
             (AwaitingIdent, Some(Ident(id))) => {
-                self.state = AwaitingDollar;
-                Some((self.tokenize)(&self.defs[&id.to_string()]))
+                let defs = self.defs;
+                let wildcard;
+                let annotation = self.annotation();
+                let def = if id == "_" {
+                    let node = match annotation {
+                        // wildcards can't be optional, or matched by name
+                        Some((_, true, _)) | Some((_, _, Some(_))) => {
+                            self.fail();
+                            return None;
+                        }
+                        Some((node, false, None)) => node,
+                        None => NodeType::Infer,
+                    };
+                    let id = u32::from(WILDCARD);
+                    wildcard = MetaDef {
+                        node,
+                        id,
+                        optional: false,
+                        name: None,
+                    };
+                    &wildcard
+                } else {
+                    // any annotation was used by declare_inline
+                    &defs[&id.to_string()]
+                };
+                if let NodeType::Infer = def.node {
+                    self.groups.inferred.borrow_mut().push((def.id, self.pattern));
+                }
+                let marked;
+                let def = if self.marked {
+                    marked = MetaDef {
+                        node: def.node.clone(),
+                        id: u32::from(WILDCARD),
+                        optional: false,
+                        name: None,
+                    };
+                    &marked
+                } else {
+                    def
+                };
+                let tt = (self.tokenize)(def);
+                if let self::NodeType::Lifetime = def.node {
+                    // a lifetime is two tokens: a tick joined to an ident
+                    self.cont.push(tt);
+                    self.state = Cont;
+                    return Some(proc_macro2::Punct::new('\'', proc_macro2::Spacing::Joint).into());
+                }
+                self.state = if self.cont.is_empty() {
+                    AwaitingDollar
+                } else {
+                    Cont
+                };
+                Some(tt)
             }
             (AwaitingIdent, Some(Punct(ref c))) if c.as_char() == '$' => {
                 self.state = AwaitingDollar;
                 Some(Punct(c.clone()))
             }
+            (AwaitingIdent, Some(Group(ref g)))
+                if g.delimiter() == proc_macro2::Delimiter::Parenthesis =>
+            {
+                self.cont = self.group(g);
+                self.cont.reverse();
+                self.state = Cont;
+                self.step()
+            }
+            // after '$', expected one of: identifier, '$', '('
             (AwaitingIdent, _) => {
-                panic!("macro body parse failure: after '$', expected one of: identifier, '$'")
+                self.fail();
+                None
             }
             (Cont, _) => unreachable!("Cont handled before advancing ts"),
         }
     }
 }
 
+impl<'a, It: Iterator<Item = TokenTree>, F: Fn(&MetaDef) -> TokenTree> MacBodyTransducer<'a, It, F> {
+    /// Expand the `$( ... )` group whose contents have just been read. A group is an alternation
+    /// if it has a top-level `|` and isn't followed by a repetition op.
+    fn group(&mut self, group: &proc_macro2::Group) -> Vec<TokenTree> {
+        // nested groups are not supported
+        if self.in_group {
+            self.fail();
+            return Vec::new();
+        }
+        use proc_macro2::Spacing;
+        let tts: Vec<_> = group.stream().into_iter().collect();
+        // a lone `|`, not part of `||` or `|=`
+        let is_bar = |i: usize| {
+            let bar = |tt: &TokenTree, spacing| {
+                matches!(tt, TokenTree::Punct(c) if c.as_char() == '|' && c.spacing() == spacing)
+            };
+            bar(&tts[i], Spacing::Alone) && !(i > 0 && bar(&tts[i - 1], Spacing::Joint))
+        };
+        let bars: Vec<_> = (0..tts.len()).filter(|&i| is_bar(i)).collect();
+        let followed_by_op = self.ts.peek().and_then(repeat_op).is_some();
+        if !followed_by_op && !bars.is_empty() {
+            let starts = std::iter::once(0).chain(bars.iter().map(|i| i + 1));
+            let ends = bars.iter().cloned().chain(std::iter::once(tts.len()));
+            let branches: Vec<_> = starts.zip(ends).map(|(a, b)| &tts[a..b]).collect();
+            let mut seen = self.groups.seen.borrow_mut();
+            let branch = match self.groups.expanded {
+                Some((i, branch)) if i == seen.len() => branch,
+                _ => 0,
+            };
+            seen.push(Group::Alternation {
+                branches: branches.len(),
+            });
+            drop(seen);
+            let mut body = self.nested(branches[branch].iter().cloned());
+            body.in_group = true;
+            return body.collect();
+        }
+        self.repetition(group)
+    }
+
+    /// Note a group, and return how many copies to expand it to.
+    fn expansion(&self, group: Group, default: usize) -> usize {
+        let mut seen = self.groups.seen.borrow_mut();
+        let copies = match self.groups.expanded {
+            Some((i, copies)) if i == seen.len() => copies,
+            _ => default,
+        };
+        seen.push(group);
+        copies
+    }
+
+    /// Expand the `..` items of a delimited list: in the parentheses of a call, gaps among
+    /// arguments; at the end of a struct literal's braces, the rest of its fields. A gap is expanded
+    /// to no wildcard argument, unless `expanded` says otherwise; the fields to a wildcard field
+    /// after them (1) or before them (2), or none. In patterns, and as a lone argument, `..` keeps
+    /// its usual meaning.
+    fn list_gaps(&mut self, group: &proc_macro2::Group) -> Vec<TokenTree> {
+        use proc_macro2::{Delimiter, Punct, Spacing};
+        let tts: Vec<_> = group.stream().into_iter().collect();
+        let is_comma = |tt: &TokenTree| matches!(tt, TokenTree::Punct(c) if c.as_char() == ',');
+        let is_rest = |item: &[TokenTree]| match item {
+            [TokenTree::Punct(a), TokenTree::Punct(b)] => {
+                a.as_char() == '.' && a.spacing() == Spacing::Joint && b.as_char() == '.'
+            }
+            _ => false,
+        };
+        let items: Vec<_> = tts.split(is_comma).filter(|item| !item.is_empty()).collect();
+        if self.pattern || !self.after_path() {
+            return tts;
+        }
+        if !items.iter().any(|item| is_rest(item)) {
+            return tts;
+        }
+        let wildcard = |node| MetaDef {
+            node,
+            id: u32::from(WILDCARD),
+            optional: false,
+            name: None,
+        };
+        let comma = TokenTree::from(Punct::new(',', Spacing::Alone));
+        let mut expansion = Vec::new();
+        match group.delimiter() {
+            Delimiter::Parenthesis if items.len() > 1 => {
+                for item in items {
+                    if is_rest(item) {
+                        if self.expansion(Group::Gap, 0) == 0 {
+                            continue;
+                        }
+                        expansion.push((self.tokenize)(&wildcard(NodeType::Expr)));
+                    } else {
+                        expansion.extend(item.iter().cloned());
+                    }
+                    expansion.push(comma.clone());
+                }
+            }
+            Delimiter::Brace if items[..items.len() - 1].iter().all(|item| !is_rest(item)) => {
+                let field = vec![
+                    (self.tokenize)(&wildcard(NodeType::Ident)),
+                    Punct::new(':', Spacing::Alone).into(),
+                    (self.tokenize)(&wildcard(NodeType::Expr)),
+                    comma.clone(),
+                ];
+                let copies = self.expansion(Group::Fields, 0);
+                if copies == 2 {
+                    expansion.extend(field.iter().cloned());
+                }
+                for item in &items[..items.len() - 1] {
+                    expansion.extend(item.iter().cloned());
+                    expansion.push(comma.clone());
+                }
+                if copies == 1 {
+                    expansion.extend(field);
+                }
+            }
+            _ => return tts,
+        }
+        expansion
+    }
+
+    /// Expand the `...` that has just been read to wildcard statements: none, unless `expanded`
+    /// says otherwise.
+    fn gap(&mut self) -> Vec<TokenTree> {
+        let mut seen = self.groups.seen.borrow_mut();
+        let copies = match self.groups.expanded {
+            Some((i, copies)) if i == seen.len() => copies,
+            _ => 0,
+        };
+        seen.push(Group::Gap);
+        drop(seen);
+        let wildcard = MetaDef {
+            node: NodeType::Stmt,
+            id: u32::from(WILDCARD),
+            optional: false,
+            name: None,
+        };
+        let semi = proc_macro2::Punct::new(';', proc_macro2::Spacing::Alone);
+        (0..copies)
+            .flat_map(|_| vec![(self.tokenize)(&wildcard), semi.clone().into()])
+            .collect()
+    }
+
+    /// Expand the `. ... .` that has just been read to a method call on a wildcard: none, leaving
+    /// just a `.`, unless `expanded` says otherwise.
+    fn chain_gap(&mut self) -> Vec<TokenTree> {
+        use proc_macro2::{Delimiter, Punct, Spacing};
+        let dot = TokenTree::from(Punct::new('.', Spacing::Alone));
+        if self.expansion(Group::Chain, 0) == 0 {
+            return vec![dot];
+        }
+        let wildcard = MetaDef {
+            node: NodeType::Ident,
+            id: u32::from(WILDCARD),
+            optional: false,
+            name: None,
+        };
+        let args = proc_macro2::Group::new(Delimiter::Parenthesis, TokenStream::new());
+        vec![dot.clone(), (self.tokenize)(&wildcard), args.into(), dot]
+    }
+
+    /// Expand the `$( ... ) sep op` group whose contents have just been read.
+    fn repetition(&mut self, group: &proc_macro2::Group) -> Vec<TokenTree> {
+        // after '$( ... )', expected one of: '*', '+', '?', or a separator and one of them
+        let next = match self.ts.next() {
+            Some(next) => next,
+            None => {
+                self.fail();
+                return Vec::new();
+            }
+        };
+        let (separator, op) = match repeat_op(&next) {
+            Some(op) => (None, op),
+            None => match self.ts.next().as_ref().and_then(repeat_op) {
+                Some(op) => (Some(next), op),
+                None => {
+                    self.fail();
+                    return Vec::new();
+                }
+            },
+        };
+        // the separator is joined to the op in the pattern, but not to the next repetition
+        let separator = separator.map(|tt| match tt {
+            TokenTree::Punct(c) => {
+                let mut sep = proc_macro2::Punct::new(c.as_char(), proc_macro2::Spacing::Alone);
+                sep.set_span(c.span());
+                TokenTree::Punct(sep)
+            }
+            tt => tt,
+        });
+        let mut seen = self.groups.seen.borrow_mut();
+        let (copies, marked) = match self.groups.expanded {
+            Some((i, copies)) if i == seen.len() => (copies, self.groups.marked),
+            _ => (1, false),
+        };
+        seen.push(Group::Repetition {
+            op,
+            separator: separator.clone(),
+        });
+        drop(seen);
+        let mut expansion = Vec::new();
+        for i in 0..copies {
+            if i > 0 {
+                expansion.extend(separator.clone());
+            }
+            let mut body = self.nested(group.stream().into_iter());
+            body.in_group = true;
+            body.marked = marked;
+            expansion.extend(body);
+        }
+        expansion
+    }
+}
+
+/// The requirements of a `where` clause.
+#[derive(Default)]
+pub struct WhereClause {
+    pub constraints: Vec<Constraint>,
+    pub tests: Vec<ValueTest>,
+    pub contexts: Vec<Inside>,
+    pub counts: Vec<UseCount>,
+    /// Sub-patterns that must not match in a scope around the match, not yet compiled.
+    pub negations: Vec<(Scope, TokenStream)>,
+}
+
 pub struct MetaContext {
     bindings: DefMap,
+    /// The names of the metavars that occur in a macro body or its sub-patterns.
+    used: HashSet<String>,
+}
+
+/// Read the string literal of a `~ "regex"` name constraint.
+fn name_regex(node: &NodeType, lit: Option<TokenTree>) -> Result<String> {
+    // only idents can be matched by name
+    if !matches!(node, NodeType::Ident) {
+        return Err(Error);
+    }
+    let lit = lit.ok_or(Error)?;
+    let lit = syn::parse2::<syn::LitStr>(std::iter::once(lit).collect()).map_err(|_| Error)?;
+    Ok(lit.value())
+}
+
+/// Parse the literal a `where` clause compares a metavar with.
+fn literal(tt: Option<TokenTree>) -> Result<syn::Lit> {
+    let tt = tt.ok_or(Error)?;
+    syn::parse2(std::iter::once(tt).collect()).map_err(|_| Error)
+}
+
+/// The values of a literal metavar that `op` relates to a literal, or of an ident metavar whose
+/// name matches a regex.
+fn literal_value(op: &str, node: &NodeType, lit: syn::Lit) -> Result<Value> {
+    let discrim = lit_discrim(&lit);
+    let value = match (op, node, &lit) {
+        ("~", NodeType::Ident, syn::Lit::Str(regex)) => Value::Name(regex.value()),
+        // idents can only be matched by name, with ~
+        (_, NodeType::Ident, _) => return Err(Error),
+        (_, NodeType::Lit, _) => match (op, &lit) {
+            ("~", syn::Lit::Str(regex)) => Value::Str {
+                discrim,
+                regex: regex.value(),
+            },
+            ("==", syn::Lit::Str(x)) | ("!=", syn::Lit::Str(x)) => Value::Str {
+                discrim,
+                regex: format!("^{}$", regex::escape(&x.value())),
+            },
+            ("==", syn::Lit::Float(x)) | ("!=", syn::Lit::Float(x)) => Value::Float {
+                discrim,
+                value: x.value(),
+            },
+            ("==", syn::Lit::Bool(x)) | ("!=", syn::Lit::Bool(x)) => Value::Bool {
+                discrim,
+                value: x.value,
+            },
+            (op, syn::Lit::Int(x)) => {
+                let x = x.value();
+                // an empty range where there are no such values
+                let (min, max) = match op {
+                    "==" | "!=" => (x, x),
+                    "<" => x.checked_sub(1).map_or((1, 0), |x| (0, x)),
+                    "<=" => (0, x),
+                    ">" => x.checked_add(1).map_or((1, 0), |x| (x, u64::MAX)),
+                    ">=" => (x, u64::MAX),
+                    _ => return Err(Error),
+                };
+                Value::Int { discrim, min, max }
+            }
+            _ => return Err(Error),
+        },
+        // only literals and idents can be tested by value
+        _ => return Err(Error),
+    };
+    Ok(value)
+}
+
+/// Read the construct named by an `inside` requirement: `unsafe`, `loop`, `closure`, `async`,
+/// `impl Trait` or `impl Trait for Type`, or `#[test] fn`. A metavar as the type of an impl stands
+/// for any type. A block after a keyword, like `unsafe { .. }`, is ignored.
+fn context(ts: &mut Peekable<proc_macro2::token_stream::IntoIter>) -> Result<Context> {
+    use proc_macro2::TokenTree::*;
+    let context = match ts.next() {
+        Some(Ident(ref id)) if id == "unsafe" => Context::Unsafe,
+        Some(Ident(ref id)) if id == "loop" => Context::Loop,
+        Some(Ident(ref id)) if id == "closure" => Context::Closure,
+        Some(Ident(ref id)) if id == "async" => Context::Async,
+        Some(Ident(ref id)) if id == "impl" => {
+            let trait_ = path_name(ts).ok_or(Error)?;
+            let ty = match ts.peek() {
+                Some(Ident(ref id)) if id == "for" => {
+                    ts.next();
+                    match ts.peek() {
+                        Some(Punct(ref c)) if c.as_char() == '$' => {
+                            ts.next();
+                            ts.next();
+                            None
+                        }
+                        _ => Some(path_name(ts).ok_or(Error)?),
+                    }
+                }
+                _ => None,
+            };
+            Context::Impl { trait_, ty }
+        }
+        Some(Punct(ref c)) if c.as_char() == '#' => {
+            match (ts.next(), ts.next()) {
+                (Some(Group(ref attr)), Some(Ident(ref id)))
+                    if attr.stream().to_string() == "test" && id == "fn" => {}
+                _ => return Err(Error),
+            }
+            Context::Test
+        }
+        _ => return Err(Error),
+    };
+    if let Some(Group(ref g)) = ts.peek() {
+        if g.delimiter() == proc_macro2::Delimiter::Brace {
+            ts.next();
+        }
+    }
+    Ok(context)
+}
+
+/// Read a path, returning the name in its last segment. Generic arguments, and the return type of
+/// a `Fn() -> T` path, are skipped. Unbalanced angle brackets make it `None`.
+fn path_name(ts: &mut Peekable<proc_macro2::token_stream::IntoIter>) -> Option<String> {
+    use proc_macro2::{Spacing, TokenTree::*};
+    let mut name = None;
+    let mut depth = 0usize;
+    // after a `->` at the top level
+    let mut output = false;
+    let mut arrow = false;
+    loop {
+        let after_minus = std::mem::replace(&mut arrow, false);
+        match ts.peek() {
+            Some(Ident(ref id)) if depth == 0 && id == "for" => break,
+            Some(Ident(ref id)) if depth == 0 && !output => name = Some(id.to_string()),
+            Some(Punct(ref c)) if c.as_char() == '-' => arrow = c.spacing() == Spacing::Joint,
+            Some(Punct(ref c)) if c.as_char() == '>' && after_minus => output |= depth == 0,
+            Some(Punct(ref c)) if c.as_char() == '<' => depth += 1,
+            Some(Punct(ref c)) if c.as_char() == '>' => depth = depth.checked_sub(1)?,
+            Some(Punct(ref c)) if c.as_char() == ',' && depth == 0 => break,
+            Some(_) => (),
+            None => break,
+        }
+        ts.next();
+    }
+    name
+}
+
+/// Read the scope named by a keyword: `within` a match, `after` it, or `anywhere`.
+fn scope(id: &proc_macro2::Ident) -> Option<Scope> {
+    match &id.to_string()[..] {
+        "within" => Some(Scope::Within),
+        "after" => Some(Scope::After),
+        "anywhere" => Some(Scope::Anywhere),
+        _ => None,
+    }
 }
 
-fn parse_args(ts: TokenStream) -> DefMap {
+/// The numbers of uses that `op` relates to an integer literal, as an inclusive range.
+fn count_range(op: &str, lit: syn::Lit) -> Result<(usize, usize)> {
+    let x = match lit {
+        syn::Lit::Int(x) => usize::try_from(x.value()).map_err(|_| Error)?,
+        _ => return Err(Error),
+    };
+    // an empty range where there are no such counts
+    let range = match op {
+        "==" => (x, x),
+        "<" => x.checked_sub(1).map_or((1, 0), |x| (0, x)),
+        "<=" => (0, x),
+        ">" => (x.checked_add(1).ok_or(Error)?, usize::MAX),
+        ">=" => (x, usize::MAX),
+        _ => return Err(Error),
+    };
+    Ok(range)
+}
+
+/// Parse the integer range of an `in` relation: `a..b`, `a..=b`, or `a..`.
+fn int_range(
+    node: &NodeType,
+    ts: &mut Peekable<proc_macro2::token_stream::IntoIter>,
+) -> Result<Value> {
+    use proc_macro2::TokenTree::*;
+    // only literals can be tested by value
+    if !matches!(node, NodeType::Lit) {
+        return Err(Error);
+    }
+    let int = |lit: &syn::Lit| match lit {
+        syn::Lit::Int(x) => Ok(x.value()),
+        _ => Err(Error),
+    };
+    let min = literal(ts.next())?;
+    let discrim = lit_discrim(&min);
+    let min = int(&min)?;
+    for _ in 0..2 {
+        match ts.next() {
+            Some(Punct(ref c)) if c.as_char() == '.' => (),
+            _ => return Err(Error),
+        }
+    }
+    let inclusive = match ts.peek() {
+        Some(Punct(ref c)) if c.as_char() == '=' => ts.next().is_some(),
+        _ => false,
+    };
+    let max = match ts.peek() {
+        Some(Literal(_)) => {
+            let max = int(&literal(ts.next())?)?;
+            if inclusive {
+                max
+            } else if let Some(max) = max.checked_sub(1) {
+                max
+            } else {
+                return Ok(Value::Int {
+                    discrim,
+                    min: 1,
+                    max: 0,
+                });
+            }
+        }
+        _ => u64::MAX,
+    };
+    Ok(Value::Int { discrim, min, max })
+}
+
+fn parse_args(ts: TokenStream) -> Result<DefMap> {
     let mut ts = ts.into_iter();
     let mut args = HashMap::new();
     loop {
@@ -141,58 +956,475 @@ fn parse_args(ts: TokenStream) -> DefMap {
         match ts.next() {
             Some(Punct(ref c)) if c.as_char() == '$' => (),
             None => break,
-            _ => panic!(),
+            _ => return Err(Error),
         }
         let id = match ts.next() {
             Some(Ident(id)) => id.to_string(),
-            _ => panic!(),
+            _ => return Err(Error),
         };
         match ts.next() {
             Some(Punct(ref c)) if c.as_char() == ':' => (),
-            _ => panic!(),
+            _ => return Err(Error),
         }
         let node = match ts.next() {
             Some(Ident(typ)) => typ,
-            _ => panic!(),
+            _ => return Err(Error),
         };
-        let node = match node.to_string().as_ref() {
-            "ident" => self::NodeType::Ident,
-            "expr" => self::NodeType::Expr,
-            _ => panic!(),
+        let node = NodeType::from_name(&node.to_string()).ok_or(Error)?;
+        let mut next = ts.next();
+        let optional = match next {
+            Some(Punct(ref c)) if c.as_char() == '?' => {
+                next = ts.next();
+                true
+            }
+            _ => false,
+        };
+        let name = match next {
+            Some(Punct(ref c)) if c.as_char() == '~' => {
+                let name = name_regex(&node, ts.next())?;
+                next = ts.next();
+                Some(name)
+            }
+            _ => None,
         };
         let def = MetaDef {
             node,
             id: (args.len() + 1) as u32,
+            optional,
+            name,
         };
-        let prev_def = args.insert(id, def);
-        assert!(prev_def.is_none());
-        match ts.next() {
+        def.mvar()?;
+        if args.insert(id, def).is_some() {
+            return Err(Error);
+        }
+        match next {
             Some(Punct(ref c)) if c.as_char() == ',' => (),
             None => break,
-            _ => panic!(),
+            _ => return Err(Error),
+        }
+    }
+    Ok(args)
+}
+
+/// Split a macro body from the `where` clause that may end it.
+pub fn split_where(body: TokenStream) -> (TokenStream, TokenStream) {
+    let mut body = body.into_iter();
+    let head = body
+        .by_ref()
+        .take_while(|tt| match tt {
+            TokenTree::Ident(id) => id != "where",
+            _ => true,
+        })
+        .collect();
+    (head, body.collect())
+}
+
+/// Split a macro body from the `^` that may start it and the `$` that may end it, anchoring its
+/// statements to the start and end of a block.
+pub fn split_boundaries(body: TokenStream) -> (TokenStream, bool, bool) {
+    let is_punct = |tt: Option<&TokenTree>, ch| match tt {
+        Some(TokenTree::Punct(c)) => c.as_char() == ch,
+        _ => false,
+    };
+    let mut tts: Vec<_> = body.into_iter().collect();
+    let start = is_punct(tts.first(), '^');
+    if start {
+        tts.remove(0);
+    }
+    let end = is_punct(tts.last(), '$') && !is_punct(tts.iter().rev().nth(1), '$');
+    if end {
+        tts.pop();
+    }
+    (tts.into_iter().collect(), start, end)
+}
+
+/// The statements of a macro body written as a set, `${ ... }`, to be matched in any order.
+pub fn split_set(body: &TokenStream) -> Option<TokenStream> {
+    let tts: Vec<_> = body.clone().into_iter().collect();
+    match &tts[..] {
+        [TokenTree::Punct(c), TokenTree::Group(g)]
+            if c.as_char() == '$' && g.delimiter() == proc_macro2::Delimiter::Brace =>
+        {
+            Some(g.stream())
+        }
+        _ => None,
+    }
+}
+
+/// Whether a macro body has a `$( ... )` group within another, which isn't supported.
+pub fn has_nested_group(body: &TokenStream) -> bool {
+    fn nested(ts: TokenStream, in_group: bool) -> bool {
+        let mut dollar = false;
+        for tt in ts {
+            match tt {
+                TokenTree::Group(g) => {
+                    let group = dollar && g.delimiter() == proc_macro2::Delimiter::Parenthesis;
+                    if group && in_group || nested(g.stream(), in_group || group) {
+                        return true;
+                    }
+                    dollar = false;
+                }
+                // `$$` is an escaped `$`
+                TokenTree::Punct(ref c) if c.as_char() == '$' => dollar = !dollar,
+                _ => dollar = false,
+            }
         }
+        false
     }
-    args
+    nested(body.clone(), false)
 }
 
 impl MetaContext {
     /// A macro's args declaration creates a context associating AST types with metavar names.
-    pub fn new(ts: TokenStream) -> Self {
-        let bindings = parse_args(ts);
+    pub fn new(ts: TokenStream) -> Result<Self> {
+        let bindings = parse_args(ts)?;
         trace!("bindings={:?}", bindings);
-        MetaContext { bindings }
+        Ok(MetaContext {
+            bindings,
+            used: HashSet::new(),
+        })
     }
 
-    pub fn apply(&self, ts: TokenStream) -> (TokenStream, TokenStream) {
-        let nodes =
-            MacBodyTransducer::new(ts.clone().into_iter(), &self.bindings, &MetaDef::node_token);
-        let ids = MacBodyTransducer::new(ts.into_iter(), &self.bindings, &MetaDef::id_token);
-        (nodes.collect(), ids.collect())
+    /// Declare the metavars that a macro body uses without their being declared in its args: with
+    /// the kind annotating their first use (`$x:expr`), or if there is none, a kind to be inferred
+    /// from where the placeholder parses.
+    pub fn declare_inline(&mut self, ts: &TokenStream) -> Result<()> {
+        use proc_macro2::TokenTree::*;
+        let tts: Vec<_> = ts.clone().into_iter().collect();
+        let mut i = 0;
+        while i < tts.len() {
+            let name = match (&tts[i], tts.get(i + 1)) {
+                (Group(g), _) => {
+                    self.declare_inline(&g.stream())?;
+                    i += 1;
+                    continue;
+                }
+                (Punct(c), Some(Punct(d))) if c.as_char() == '$' && d.as_char() == '$' => {
+                    i += 2;
+                    continue;
+                }
+                (Punct(c), Some(Ident(name))) if c.as_char() == '$' => {
+                    name.to_string()
+                }
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            i += 2;
+            let annotation = match (tts.get(i), tts.get(i + 1)) {
+                (Some(Punct(c)), Some(Ident(kind))) if c.as_char() == ':' => {
+                    NodeType::from_name(&kind.to_string())
+                }
+                _ => None,
+            };
+            let (node, optional, regex) = match annotation {
+                Some(node) => {
+                    i += 2;
+                    let optional = matches!(tts.get(i), Some(Punct(c)) if c.as_char() == '?');
+                    if optional {
+                        i += 1;
+                    }
+                    let regex = match tts.get(i) {
+                        Some(Punct(c)) if c.as_char() == '~' => {
+                            Some(name_regex(&node, tts.get(i + 1).cloned())?)
+                        }
+                        _ => None,
+                    };
+                    (node, optional, regex)
+                }
+                None => (NodeType::Infer, false, None),
+            };
+            if name == "_" {
+                // wildcards can't be optional, or matched by name
+                if optional || regex.is_some() {
+                    return Err(Error);
+                }
+                continue;
+            }
+            self.used.insert(name.clone());
+            if !self.bindings.contains_key(&name) {
+                let id = (self.bindings.len() + 1) as u32;
+                let def = MetaDef {
+                    node,
+                    id,
+                    optional,
+                    name: regex,
+                };
+                def.mvar()?;
+                self.bindings.insert(name, def);
+            }
+        }
+        trace!("bindings={:?}", self.bindings);
+        Ok(())
+    }
+
+    /// Parse the comma-separated relations of a `where` clause. A metavar can be related to
+    /// another (`$a != $b`, `$c == $d`), or its value tested: a literal against a literal
+    /// (`$n == 0`, `$f != 1.0`, `$b == true`), an integer literal against a bound (`$n < 8`) or
+    /// range (`$n in 1..=4`), or an ident's name or string literal's contents against a regex
+    /// (`$s ~ "^tmp"`). Literals are unsigned, as in Rust's syntax, where `-1` is a negation of a
+    /// literal: a `lit` metavar never binds it, and negative values and bounds are rejected. A match can also be required to be `inside` a construct, or `not inside`
+    /// one; see `context`. A sub-pattern in braces can be required `not within` the match, `not
+    /// after` it, or `not anywhere`, sharing the pattern's metavars: `not after { $x }`. Finally,
+    /// the uses of a metavar's binding in one of those scopes can be counted (`$x used after == 1`,
+    /// `$y used anywhere >= 2`).
+    pub fn where_clause(&self, ts: TokenStream) -> Result<WhereClause> {
+        use proc_macro2::TokenTree::*;
+        let mut ts = ts.into_iter().peekable();
+        let mut clause = WhereClause::default();
+        let WhereClause {
+            constraints,
+            tests,
+            contexts,
+            counts,
+            negations,
+        } = &mut clause;
+        loop {
+            let negated = match ts.peek() {
+                Some(Ident(ref id)) if id == "not" => ts.next().is_some(),
+                _ => false,
+            };
+            match ts.peek() {
+                Some(Ident(ref id)) if id == "inside" => {
+                    ts.next();
+                    let context = context(&mut ts)?;
+                    contexts.push(Inside { negated, context });
+                    match ts.next() {
+                        Some(Punct(ref c)) if c.as_char() == ',' => continue,
+                        None => break,
+                        _ => return Err(Error),
+                    }
+                }
+                Some(Ident(ref id)) if negated && scope(id).is_some() => {
+                    let scope = scope(id).unwrap();
+                    ts.next();
+                    match ts.next() {
+                        Some(Group(ref g)) if g.delimiter() == proc_macro2::Delimiter::Brace => {
+                            negations.push((scope, g.stream()))
+                        }
+                        _ => return Err(Error),
+                    }
+                    match ts.next() {
+                        Some(Punct(ref c)) if c.as_char() == ',' => continue,
+                        None => break,
+                        _ => return Err(Error),
+                    }
+                }
+                // `not` must be followed by `inside` or a scope
+                _ if negated => return Err(Error),
+                _ => (),
+            }
+            let (mvar, def) = self.metavar(&mut ts)?;
+            let used = match ts.peek() {
+                Some(Ident(ref id)) if id == "used" => {
+                    ts.next();
+                    match ts.next() {
+                        Some(Ident(ref id)) => Some(scope(id).ok_or(Error)?),
+                        _ => return Err(Error),
+                    }
+                }
+                _ => None,
+            };
+            let op = match ts.next() {
+                Some(Ident(ref id)) if id == "in" => "in".to_owned(),
+                Some(Punct(c)) => {
+                    let mut op = c.as_char().to_string();
+                    if c.spacing() == proc_macro2::Spacing::Joint {
+                        match ts.next() {
+                            Some(Punct(d)) => op.push(d.as_char()),
+                            _ => return Err(Error),
+                        }
+                    }
+                    op
+                }
+                _ => return Err(Error),
+            };
+            if !["==", "!=", "<", "<=", ">", ">=", "~", "in"].contains(&&op[..]) {
+                return Err(Error);
+            }
+            if let Some(scope) = used {
+                let (min, max) = count_range(&op, literal(ts.next())?)?;
+                counts.push(UseCount {
+                    mvar,
+                    scope,
+                    min,
+                    max,
+                });
+                match ts.next() {
+                    Some(Punct(ref c)) if c.as_char() == ',' => continue,
+                    None => break,
+                    _ => return Err(Error),
+                }
+            }
+            match ts.peek() {
+                Some(Punct(ref c)) if c.as_char() == '$' => {
+                    let (b, _) = self.metavar(&mut ts)?;
+                    // a metavar can only be constrained relative to another, with == or !=
+                    let equal = match &op[..] {
+                        "==" => true,
+                        "!=" => false,
+                        _ => return Err(Error),
+                    };
+                    if mvar == b {
+                        return Err(Error);
+                    }
+                    constraints.push(Constraint { a: mvar, b, equal });
+                }
+                _ => {
+                    let value = if op == "in" {
+                        int_range(&def.node, &mut ts)?
+                    } else {
+                        literal_value(&op, &def.node, literal(ts.next())?)?
+                    };
+                    let negated = op == "!=";
+                    tests.push(ValueTest { mvar, negated, value });
+                }
+            }
+            match ts.next() {
+                Some(Punct(ref c)) if c.as_char() == ',' => (),
+                None => break,
+                _ => return Err(Error),
+            }
+        }
+        Ok(clause)
+    }
+
+    /// Read a `$name` that refers to a declared metavar, returning its id and its definition.
+    fn metavar(
+        &self,
+        ts: &mut Peekable<proc_macro2::token_stream::IntoIter>,
+    ) -> Result<(u8, &MetaDef)> {
+        use proc_macro2::TokenTree::*;
+        match ts.next() {
+            Some(Punct(ref c)) if c.as_char() == '$' => (),
+            _ => return Err(Error),
+        }
+        let def = match ts.next() {
+            Some(Ident(id)) => self.bindings.get(&id.to_string()).ok_or(Error)?,
+            _ => return Err(Error),
+        };
+        Ok((def.mvar()?, def))
+    }
+
+    /// Make each metavar of inferred kind that is used both in a pattern and elsewhere an ident, as
+    /// in `let $t = $x; drop($t);`: a binding in a pattern and a use in an expression have only
+    /// the ident in common.
+    pub fn infer_idents(&mut self, streams: &[&TokenStream]) -> Result<()> {
+        let mut uses = Vec::new();
+        for ts in streams {
+            let groups = Groups::new(None, false);
+            let transducer = MacBodyTransducer::new(
+                (*ts).clone().into_iter(),
+                &self.bindings,
+                &MetaDef::node_token,
+                &groups,
+            );
+            transducer.for_each(drop);
+            if groups.failed.get() {
+                return Err(Error);
+            }
+            uses.extend(groups.inferred.into_inner());
+        }
+        for def in self.bindings.values_mut() {
+            let in_pattern = |pattern| uses.contains(&(def.id, pattern));
+            if let NodeType::Infer = def.node {
+                if in_pattern(true) && in_pattern(false) {
+                    def.node = NodeType::Ident;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every metavar declared in the args occurs in the body or a sub-pattern, once
+    /// they have all been declared inline.
+    pub fn check_used(&self) -> Result<()> {
+        if self.bindings.keys().all(|name| self.used.contains(name)) {
+            Ok(())
+        } else {
+            Err(Error)
+        }
+    }
+
+    /// The ids of metavars declared optional.
+    pub fn optional_ids(&self) -> Result<Vec<u8>> {
+        let mut ids = self
+            .bindings
+            .values()
+            .filter(|def| def.optional)
+            .map(MetaDef::mvar)
+            .collect::<Result<Vec<_>>>()?;
+        ids.sort_unstable();
+        Ok(ids)
     }
-}
 
-// for ergonomics, need to support $_ at least when its unambiguously parsable
-//let $baz: $_ = $foo;
+    /// The ids of the named metavars.
+    pub fn ids(&self) -> Result<HashMap<String, u8>> {
+        self.bindings
+            .iter()
+            .map(|(name, def)| Ok((name.clone(), def.mvar()?)))
+            .collect()
+    }
+
+    /// Tests of the regexes that the names of ident metavars are declared to match.
+    pub fn name_tests(&self) -> Result<Vec<ValueTest>> {
+        let mut tests = Vec::new();
+        for def in self.bindings.values() {
+            if let Some(name) = &def.name {
+                tests.push(ValueTest {
+                    mvar: def.mvar()?,
+                    negated: false,
+                    value: Value::Name(name.clone()),
+                });
+            }
+        }
+        tests.sort_unstable_by_key(|test| test.mvar);
+        Ok(tests)
+    }
+
+    /// Expand a macro body to standard syntax with placeholders for its metavars. Each group is
+    /// expanded to one copy or its first branch, except that `expanded` can specify a number of
+    /// copies or a branch for one group by index. Returns streams with node placeholders and with
+    /// id placeholders, and the groups.
+    pub fn apply(
+        &self,
+        ts: TokenStream,
+        expanded: Option<(usize, usize)>,
+    ) -> Result<(TokenStream, TokenStream, Vec<Group>)> {
+        self.transduce(ts, expanded, false)
+    }
+
+    /// Like `apply` with one copy of a repetition group, but with the metavars in the group's
+    /// copy as wildcards.
+    pub fn apply_marked(&self, ts: TokenStream, group: usize) -> Result<(TokenStream, TokenStream)> {
+        let (nodes, ids, _) = self.transduce(ts, Some((group, 1)), true)?;
+        Ok((nodes, ids))
+    }
+
+    fn transduce(
+        &self,
+        ts: TokenStream,
+        expanded: Option<(usize, usize)>,
+        marked: bool,
+    ) -> Result<(TokenStream, TokenStream, Vec<Group>)> {
+        let groups = Groups::new(expanded, marked);
+        let nodes = MacBodyTransducer::new(
+            ts.clone().into_iter(),
+            &self.bindings,
+            &MetaDef::node_token,
+            &groups,
+        )
+        .collect();
+        let id_groups = Groups::new(expanded, marked);
+        let ids = MacBodyTransducer::new(ts.into_iter(), &self.bindings, &MetaDef::id_token, &id_groups)
+            .collect();
+        if groups.failed.get() || id_groups.failed.get() {
+            return Err(Error);
+        }
+        Ok((nodes, ids, groups.seen.into_inner()))
+    }
+}
 
 // macro type system
 /*
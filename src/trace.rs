@@ -1,7 +1,9 @@
 //! A regex language for trees
 
 use log::trace;
+use regex::Regex;
 use std::fmt::Debug;
+use std::ops::Range;
 
 #[derive(PartialEq, Eq, Clone)]
 pub struct Trace {
@@ -11,6 +13,32 @@ pub struct Trace {
 const META: u8 = 255;
 const OPEN: u8 = 254;
 const CLOSE: u8 = 253;
+// repetition groups; only occur in patterns
+const REPEAT: u8 = 252;
+const SEPARATOR: u8 = 251;
+const END: u8 = 250;
+// precedes a metavar whose subtree may be absent; only occurs in patterns
+const OPTIONAL: u8 = 249;
+// alternation groups; only occur in patterns
+const ALTERNATE: u8 = 248;
+// a relation between two metavars' bindings; only occurs in patterns, before anything else
+const CONSTRAINT: u8 = 247;
+// a test of the value a metavar binds; only occurs in patterns, before anything else
+const TEST: u8 = 246;
+// any number of sibling subtrees; only occurs in patterns
+const GAP: u8 = 245;
+// sibling subtrees in any order, among any others; only occurs in patterns
+const UNORDERED: u8 = 244;
+// a subtree nested in any number of method calls' receivers; only occurs in patterns
+const CHAIN: u8 = 243;
+// the start or end of a block: only occurs in patterns, before or after all the statements
+const BOUNDARY: u8 = 242;
+
+/// Metavar that matches any subtree without binding it.
+pub(crate) const WILDCARD: u8 = 0;
+
+/// Metavar ids are below the bytes reserved for other symbols.
+pub(crate) const MVAR_LIMIT: u8 = BOUNDARY;
 
 #[derive(Debug)]
 pub struct IndexedTrace {
@@ -19,9 +47,9 @@ pub struct IndexedTrace {
 }
 
 impl IndexedTrace {
-    fn traces(&self) -> Traces {
+    fn traces(&self) -> Traces<'_> {
         Traces {
-            master: &self,
+            master: self,
             i: 0,
         }
     }
@@ -30,6 +58,41 @@ impl IndexedTrace {
     pub fn deindex(self) -> Trace {
         self.trace
     }
+
+    /// The end of the block around a span of the input: where the block that holds the innermost
+    /// statement containing or starting the span closes, or the end of the input.
+    fn block_end(&self, span: &Range<usize>) -> usize {
+        let buf = &self.trace.buf;
+        let subtree_end = |start: usize| {
+            // past the statement's opening
+            let mut syms = Symbols {
+                buf: &buf[start + 2..],
+            };
+            close_subtree(&mut syms);
+            buf.len() - syms.buf.len()
+        };
+        let stmt_end = self
+            .indexes
+            .iter()
+            .rev()
+            .filter(|&&i| i <= span.start)
+            .map(|&i| (i, subtree_end(i)))
+            .find(|&(i, end)| i == span.start || end >= span.end)
+            .map_or(span.end, |(_, end)| end);
+        let mut syms = Symbols {
+            buf: &buf[stmt_end..],
+        };
+        let mut depth = 0usize;
+        while let Some(s) = syms.next() {
+            match s {
+                Symbol::Open => depth += 1,
+                Symbol::Close if depth == 0 => return buf.len() - syms.buf.len() - CLOSER_LEN,
+                Symbol::Close => depth -= 1,
+                _ => (),
+            }
+        }
+        buf.len()
+    }
 }
 
 struct Traces<'a> {
@@ -57,27 +120,105 @@ impl<'a> Iterator for Traces<'a> {
 
 pub struct ToplevelMatches<'a, 'b> {
     pattern: Symbols<'a>,
+    tests: Vec<Test>,
     inputs: Traces<'b>,
+    // only match at the first statement of a block
+    from_start: bool,
 }
 
 impl Iterator for ToplevelMatches<'_, '_> {
-    type Item = usize;
+    /// The index of the first matched statement among all the statements of the input, at any
+    /// depth; the pattern as it matched; the positions relative to the first matched statement of
+    /// the first statement after each gap and of each statement matched by a set; and the site.
+    type Item = (usize, Trace, Vec<usize>, Site);
     fn next(&mut self) -> Option<Self::Item> {
-        for (i, mut input) in self.inputs.by_ref() {
-            if is_match(self.pattern.clone(), &mut input) {
-                return Some(i);
+        let buf = &self.inputs.master.trace.buf;
+        for (i, input) in self.inputs.by_ref() {
+            let start = buf.len() - input.buf.len();
+            // the first statement of a block follows the block's opening
+            if self.from_start && start != 0 && buf[start - 2..start] != [META, OPEN] {
+                continue;
+            }
+            if let Some(found) = match_prefix(self.pattern.clone(), &self.tests, input, Vec::new()) {
+                let anchors = found
+                    .anchors
+                    .iter()
+                    .map(|&end| toplevel_len(&found.matched.buf[..end]))
+                    .collect();
+                let site = found.site(start..buf.len() - found.rest.buf.len());
+                return Some((i, found.matched, anchors, site));
             }
         }
         None
     }
 }
 
+/// Where a match was found in the input, and what its metavars bound there.
+pub struct Site {
+    span: Range<usize>,
+    bindings: Vec<Option<Vec<u8>>>,
+}
+
+impl Site {
+    fn span(&self, input: &IndexedTrace, scope: Scope) -> Range<usize> {
+        match scope {
+            Scope::Within => self.span.clone(),
+            Scope::After => self.span.end..input.block_end(&self.span),
+            Scope::Anywhere => 0..input.trace.buf.len(),
+        }
+    }
+
+    /// Count the subtrees in a scope of the input that are the same as a metavar's binding.
+    pub fn uses(&self, input: &IndexedTrace, mvar: u8, scope: Scope) -> usize {
+        let bound = match self.bindings.get(usize::from(mvar)) {
+            Some(Some(bound)) => bound,
+            _ => return 0,
+        };
+        let mut subtree = vec![META, OPEN];
+        subtree.extend_from_slice(bound);
+        subtree.extend_from_slice(&[META, CLOSE]);
+        let mut input = Symbols {
+            buf: &input.trace.buf[self.span(input, scope)],
+        };
+        let mut n = 0;
+        while !input.buf.is_empty() {
+            if input.buf.starts_with(&subtree) {
+                n += 1;
+            }
+            input.next();
+        }
+        n
+    }
+}
+
+/// Where a sub-pattern or a metavar's binding is looked for, relative to a match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// `within`: inside the match.
+    Within,
+    /// `after`: anywhere in the rest of the block around the match.
+    After,
+    /// `anywhere`: anywhere in the input, e.g. the whole body of the fn around the match.
+    Anywhere,
+}
+
 #[derive(PartialEq, Eq, Debug)]
 enum Symbol {
     Meta(u8),
     Literal(u8),
     Open,
     Close,
+    Repeat(u8),
+    Separator,
+    End,
+    Optional,
+    Alternate,
+    Constraint(Constraint),
+    Test(u8),
+    Gap,
+    Unordered,
+    Chain,
+    Boundary,
 }
 
 #[derive(Clone)]
@@ -91,12 +232,40 @@ impl<'a> Iterator for Symbols<'a> {
     fn next(&mut self) -> Option<Symbol> {
         if let Some((&x, rest)) = self.buf.split_first() {
             if x == META {
-                let ((&x, rest)) = rest.split_first().unwrap();
+                let (&x, rest) = rest.split_first().unwrap();
                 self.buf = rest;
                 Some(match x {
                     META => Symbol::Literal(META),
                     OPEN => Symbol::Open,
                     CLOSE => Symbol::Close,
+                    REPEAT => {
+                        let (&op, rest) = rest.split_first().unwrap();
+                        self.buf = rest;
+                        Symbol::Repeat(op)
+                    }
+                    SEPARATOR => Symbol::Separator,
+                    END => Symbol::End,
+                    OPTIONAL => Symbol::Optional,
+                    ALTERNATE => Symbol::Alternate,
+                    GAP => Symbol::Gap,
+                    UNORDERED => Symbol::Unordered,
+                    CHAIN => Symbol::Chain,
+                    BOUNDARY => Symbol::Boundary,
+                    CONSTRAINT => {
+                        let (c, rest) = rest.split_at(3);
+                        self.buf = rest;
+                        Symbol::Constraint(Constraint {
+                            a: c[0],
+                            b: c[1],
+                            equal: c[2] == b'=',
+                        })
+                    }
+                    TEST => {
+                        let (x, rest) = rest.split_at(3);
+                        let len = usize::from(u16::from_le_bytes([x[1], x[2]]));
+                        self.buf = &rest[len..];
+                        Symbol::Test(x[0])
+                    }
                     x => Symbol::Meta(x),
                 })
             } else {
@@ -111,12 +280,12 @@ impl<'a> Iterator for Symbols<'a> {
     fn size_hint(&self) -> (usize, Option<usize>) {
         // from 0 to half the characters remaining, rounded down, are metas that won't become
         // independent symbols
-        ((self.buf.len() + 1) / 2, Some(self.buf.len()))
+        (self.buf.len().div_ceil(2), Some(self.buf.len()))
     }
 }
 
 impl Trace {
-    fn symbols(&self) -> Symbols {
+    fn symbols(&self) -> Symbols<'_> {
         Symbols { buf: &self.buf }
     }
 }
@@ -135,6 +304,20 @@ impl Debug for Symbols<'_> {
                 Symbol::Meta(x) => write!(f, "${}", u32::from(x))?,
                 Symbol::Open => write!(f, "[")?,
                 Symbol::Close => write!(f, "]")?,
+                Symbol::Repeat(op) => write!(f, "${}(", char::from(op))?,
+                Symbol::Separator => write!(f, "|")?,
+                Symbol::End => write!(f, ")")?,
+                Symbol::Optional => write!(f, "?")?,
+                Symbol::Alternate => write!(f, "$(")?,
+                Symbol::Constraint(c) => {
+                    let rel = if c.equal { "==" } else { "!=" };
+                    write!(f, "${}{}${};", c.a, rel, c.b)?
+                }
+                Symbol::Test(x) => write!(f, "${}?;", x)?,
+                Symbol::Gap => write!(f, "...")?,
+                Symbol::Unordered => write!(f, "${{")?,
+                Symbol::Chain => write!(f, "$.(")?,
+                Symbol::Boundary => write!(f, "^")?,
             }
         }
         Ok(())
@@ -277,6 +460,7 @@ impl<'t> ReTracer<'t> {
     }
 
     /// on failure, nothing has been consumed
+    #[allow(clippy::result_unit_err)]
     pub fn open_subtree(&mut self) -> Result<(), ()> {
         //trace!("ReTracer::open_subtree: diff_depth={}", self.diff_depth);
         if self.diff_depth != 0 {
@@ -297,6 +481,7 @@ impl<'t> ReTracer<'t> {
     // - count depth within dead subtree
 
     /// on failure, this mismatching subtree has been consumed
+    #[allow(clippy::result_unit_err)]
     pub fn close_subtree(&mut self) -> Result<(), ()> {
         //trace!("ReTracer::close_subtree: diff_depth={}", self.diff_depth);
         if self.diff_depth == 0 {
@@ -308,9 +493,13 @@ impl<'t> ReTracer<'t> {
             }
         }
         while self.diff_depth > 0 {
-            if self.buf[self.i] == META || self.buf[self.i + 1] == CLOSE {
+            if self.buf[self.i] == META {
+                match self.buf[self.i + 1] {
+                    OPEN => self.diff_depth += 1,
+                    CLOSE => self.diff_depth -= 1,
+                    _ => (),
+                }
                 self.i += 2;
-                self.diff_depth -= 1;
             } else {
                 self.i += 1;
             }
@@ -466,12 +655,719 @@ impl<'t> DeltaTracer<'t> {
 
 impl Trace {
     pub fn toplevel_len(&self) -> usize {
+        toplevel_len(&self.buf)
+    }
+}
+
+/// The number of top-level subtrees in a sequence of them.
+fn toplevel_len(buf: &[u8]) -> usize {
+    let mut syms = Symbols { buf };
+    let mut n = 0;
+    while let Some(s) = syms.next() {
+        match s {
+            Symbol::Open => {
+                n += 1;
+                close_subtree(&mut syms);
+            }
+            // a metavar at the top level stands for one whole subtree
+            Symbol::Meta(_) => n += 1,
+            _ => (),
+        }
+    }
+    n
+}
+
+/// A required relation between the bindings of two distinct metavars: equal, or unequal.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Constraint {
+    pub a: u8,
+    pub b: u8,
+    pub equal: bool,
+}
+
+impl Constraint {
+    fn holds(&self, a: &[u8], b: &[u8]) -> bool {
+        (a == b) == self.equal
+    }
+}
+
+/// A test of the value bound by a metavar, made as it binds.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ValueTest {
+    pub mvar: u8,
+    /// Pass where the value doesn't; a literal of another kind still fails.
+    pub negated: bool,
+    pub value: Value,
+}
+
+/// A required number of uses of a metavar's binding in a scope around the match.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct UseCount {
+    pub mvar: u8,
+    pub scope: Scope,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl UseCount {
+    pub fn allows(&self, n: usize) -> bool {
+        self.min <= n && n <= self.max
+    }
+}
+
+/// The values that pass a test. A literal kind is identified by its `syn::Lit` discriminant.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    /// An ident whose name matches a regex.
+    Name(String),
+    /// An integer literal within an inclusive range.
+    Int { discrim: u8, min: u64, max: u64 },
+    Float { discrim: u8, value: f64 },
+    Bool { discrim: u8, value: bool },
+    /// A string literal whose contents match a regex.
+    Str { discrim: u8, regex: String },
+}
+
+impl ValueTest {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![self.negated as u8];
+        match &self.value {
+            Value::Name(regex) => {
+                buf.push(b'n');
+                buf.extend_from_slice(regex.as_bytes());
+            }
+            Value::Int { discrim, min, max } => {
+                buf.extend_from_slice(&[b'i', *discrim]);
+                buf.extend_from_slice(&min.to_le_bytes());
+                buf.extend_from_slice(&max.to_le_bytes());
+            }
+            Value::Float { discrim, value } => {
+                buf.extend_from_slice(&[b'f', *discrim]);
+                buf.extend_from_slice(&value.to_bits().to_le_bytes());
+            }
+            Value::Bool { discrim, value } => buf.extend_from_slice(&[b'b', *discrim, *value as u8]),
+            Value::Str { discrim, regex } => {
+                buf.extend_from_slice(&[b's', *discrim]);
+                buf.extend_from_slice(regex.as_bytes());
+            }
+        }
+        buf
+    }
+}
+
+/// A compiled `ValueTest`.
+struct Test {
+    mvar: u8,
+    negated: bool,
+    check: Check,
+}
+
+enum Check {
+    Name(Regex),
+    Int(u8, u64, u64),
+    Float(u8, f64),
+    Bool(u8, bool),
+    Str(u8, Regex),
+}
+
+fn u64_at(buf: &[u8]) -> u64 {
+    let mut x = [0; 8];
+    x.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(x)
+}
+
+impl Test {
+    fn decode(mvar: u8, buf: &[u8]) -> Self {
+        let regex = |buf| Regex::new(std::str::from_utf8(buf).unwrap()).expect("regex was validated");
+        let check = match buf[1] {
+            b'n' => Check::Name(regex(&buf[2..])),
+            b'i' => Check::Int(buf[2], u64_at(&buf[3..]), u64_at(&buf[11..])),
+            b'f' => Check::Float(buf[2], f64::from_bits(u64_at(&buf[3..]))),
+            b'b' => Check::Bool(buf[2], buf[3] != 0),
+            b's' => Check::Str(buf[2], regex(&buf[3..])),
+            _ => unreachable!("test is well-formed"),
+        };
+        Test {
+            mvar,
+            negated: buf[0] != 0,
+            check,
+        }
+    }
+
+    /// Test the subtree a metavar bound.
+    fn passes(&self, bound: &[u8]) -> bool {
+        if let Check::Name(regex) = &self.check {
+            // an ident's subtree is its name
+            return std::str::from_utf8(bound).is_ok_and(|name| regex.is_match(name)) != self.negated;
+        }
+        let mut bytes = Vec::with_capacity(bound.len());
+        for s in (Symbols { buf: bound }) {
+            match s {
+                Symbol::Literal(x) => bytes.push(x),
+                // not a literal
+                _ => return false,
+            }
+        }
+        let (discrim, data) = match bytes.split_first() {
+            Some((&discrim, data)) => (discrim, data),
+            None => return false,
+        };
+        let kind = match &self.check {
+            Check::Name(_) => unreachable!(),
+            Check::Int(d, ..) | Check::Float(d, _) | Check::Bool(d, _) | Check::Str(d, _) => *d,
+        };
+        if discrim != kind {
+            return false;
+        }
+        let passed = match &self.check {
+            Check::Name(_) => unreachable!(),
+            // a datum of decimal digits
+            Check::Int(_, min, max) => std::str::from_utf8(&data[1..])
+                .ok()
+                .and_then(|x| x.parse::<u64>().ok())
+                .is_some_and(|x| *min <= x && x <= *max),
+            Check::Float(_, value) => data.len() == 8 && f64::from_bits(u64_at(data)) == *value,
+            Check::Bool(_, value) => data == [*value as u8],
+            Check::Str(_, regex) => std::str::from_utf8(data).is_ok_and(|x| regex.is_match(x)),
+        };
+        passed != self.negated
+    }
+}
+
+/// Where the one copy of a repetition group lies in a pattern's trace.
+#[derive(Debug)]
+pub struct Repetition {
+    op: u8,
+    start: usize,
+    len: usize,
+    separator: Vec<u8>,
+}
+
+/// Whether the bytes are a sequence of whole subtrees.
+fn is_forest(buf: &[u8]) -> bool {
+    buf.first() == Some(&META) && is_balanced(buf)
+}
+
+/// Whether the bytes are whole symbols that close every subtree they open.
+fn is_balanced(buf: &[u8]) -> bool {
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < buf.len() {
+        if buf[i] == META {
+            match buf.get(i + 1) {
+                Some(&OPEN) => depth += 1,
+                Some(&CLOSE) => match depth.checked_sub(1) {
+                    Some(d) => depth = d,
+                    None => return false,
+                },
+                Some(_) => (),
+                None => return false,
+            }
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    depth == 0
+}
+
+impl Repetition {
+    /// Find a repetition group by comparing the trace of a pattern that has fewer copies of the
+    /// group with the trace of the same pattern with one more copy. The fewer copies are one, or
+    /// for an optional group, none. The separator's trace is used if the separator is visible in
+    /// the trace (i.e. in a macro invocation). If given, `marked` is the trace with one copy whose
+    /// metavars are wildcards, which tells that copy from any identical siblings. The location
+    /// returned is that of the first copy.
+    pub(crate) fn locate(
+        op: u8,
+        fewer: &Trace,
+        more: &Trace,
+        separator: &Trace,
+        marked: Option<&Trace>,
+    ) -> Option<Self> {
+        let one = if op == b'?' { more } else { fewer };
+        let (fewer, more) = (&fewer.buf[..], &more.buf[..]);
+        let d = more.len().checked_sub(fewer.len())?;
+        let common = fewer.iter().zip(more).take_while(|(x, y)| x == y).count();
+        // where the copy with one copy differs from the marked trace, which must be in the copy
+        let differs = marked.and_then(|marked| {
+            let (one, marked) = (&one.buf[..], &marked.buf[..]);
+            let prefix = one.iter().zip(marked).take_while(|(x, y)| x == y).count();
+            let suffix = one.iter().rev().zip(marked.iter().rev()).take_while(|(x, y)| x == y).count();
+            if prefix == one.len() {
+                return None;
+            }
+            Some(prefix..one.len() - suffix.min(one.len() - prefix))
+        });
+        let mut syms = Symbols { buf: fewer };
+        loop {
+            // the new copy appears to have been inserted before any old one, so at the first
+            // copy's position the longer trace has: one copy, the separator, then the rest of the
+            // shorter trace
+            let start = fewer.len() - syms.buf.len();
+            if start > common {
+                return None;
+            }
+            if more[start + d..] == fewer[start..] {
+                let inserted = &more[start..start + d];
+                for sep in &[&separator.buf[..], &[]] {
+                    if op == b'?' && !sep.is_empty() || !inserted.ends_with(sep) {
+                        continue;
+                    }
+                    let body = &inserted[..d - sep.len()];
+                    let old_copy = op == b'?' || fewer[start..].starts_with(body);
+                    let in_copy = match &differs {
+                        Some(differs) => start <= differs.start && differs.end <= start + body.len(),
+                        None => true,
+                    };
+                    if old_copy && in_copy && is_forest(body) {
+                        return Some(Repetition {
+                            op,
+                            start,
+                            len: body.len(),
+                            separator: sep.to_vec(),
+                        });
+                    }
+                }
+            }
+            syms.next()?;
+        }
+    }
+}
+
+/// Find a gap by comparing the trace of a pattern with the trace of the same pattern with a
+/// wildcard in the gap. The location returned is where the gap is between siblings.
+pub(crate) fn locate_gap(without: &Trace, with: &Trace) -> Option<usize> {
+    let empty = Tracer::default().finish();
+    Repetition::locate(b'?', without, with, &empty, None).map(|r| r.start)
+}
+
+/// Where the subtrees that may match in any order lie in a pattern's trace.
+#[derive(Debug)]
+pub struct Unordered {
+    start: usize,
+    end: usize,
+}
+
+impl Unordered {
+    /// Find the subtrees by comparing the trace of a pattern with the traces of the same pattern
+    /// with a wildcard subtree after them, and before them.
+    pub(crate) fn locate(first: &Trace, after: &Trace, before: &Trace) -> Option<Self> {
+        let end = locate_gap(first, after)?;
+        let start = locate_gap(first, before)?;
+        if start > end || !is_balanced(&first.buf[start..end]) {
+            return None;
+        }
+        Some(Unordered { start, end })
+    }
+}
+
+/// An unordered group of the subtrees in a sequence of whole subtrees.
+fn unordered_group(buf: &[u8]) -> Vec<u8> {
+    let mut group = vec![META, UNORDERED];
+    for (i, item) in subtrees(buf).into_iter().enumerate() {
+        if i != 0 {
+            group.extend_from_slice(&[META, SEPARATOR]);
+        }
+        group.extend_from_slice(item);
+    }
+    group.extend_from_slice(&[META, END]);
+    group
+}
+
+/// Split a sequence of whole subtrees into the subtrees.
+fn subtrees(buf: &[u8]) -> Vec<&[u8]> {
+    let mut parts = Vec::new();
+    let mut syms = Symbols { buf };
+    loop {
+        let before = syms.buf;
+        match syms.next() {
+            Some(Symbol::Open) => close_subtree(&mut syms),
+            Some(_) => (),
+            None => return parts,
+        }
+        parts.push(&before[..before.len() - syms.buf.len()]);
+    }
+}
+
+/// Where the receiver of a method chain with a gap lies in a pattern's trace, and how a method call
+/// opens in the trace.
+#[derive(Debug)]
+pub struct Chain {
+    start: usize,
+    end: usize,
+    call: Vec<u8>,
+}
+
+impl Chain {
+    /// Find the receiver by comparing the trace of a pattern with the trace of the same pattern
+    /// with a method call wrapped around the receiver.
+    pub(crate) fn locate(first: &Trace, with: &Trace) -> Option<Self> {
+        let (first, with) = (&first.buf[..], &with.buf[..]);
+        let d = with.len().checked_sub(first.len())?;
+        let mut syms = Symbols { buf: first };
+        loop {
+            let start = first.len() - syms.buf.len();
+            if with[..start] != first[..start] {
+                return None;
+            }
+            match syms.next()? {
+                Symbol::Open | Symbol::Meta(_) => (),
+                _ => continue,
+            }
+            // the receiver is a whole subtree, or a metavar standing for one
+            let mut rest = Symbols { buf: &first[start..] };
+            if let Some(Symbol::Open) = rest.next() {
+                close_subtree(&mut rest);
+            }
+            let end = first.len() - rest.buf.len();
+            if with[start + d + (end - start)..] != first[end..] {
+                continue;
+            }
+            // the call opens with its discriminant, then has the receiver as its first child
+            for k in 3..d {
+                let call = &with[start..start + k];
+                let wrapped = &with[start..start + d + (end - start)];
+                if call[..2] == [META, OPEN]
+                    && !call[2..].contains(&META)
+                    && with[start + k..start + k + (end - start)] == first[start..end]
+                    && is_balanced(wrapped)
+                {
+                    return Some(Chain {
+                        start,
+                        end,
+                        call: call.to_vec(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Where the first branch of an alternation group lies in a pattern's trace, and the traces of
+/// all its branches.
+#[derive(Debug)]
+pub struct Alternation {
+    start: usize,
+    len: usize,
+    branches: Vec<Vec<u8>>,
+}
+
+/// The metavars occurring in a trace fragment.
+fn mvars(buf: &[u8]) -> Vec<u8> {
+    let mut mvars: Vec<_> = Symbols { buf }
+        .filter_map(|s| match s {
+            Symbol::Meta(x) if x != WILDCARD => Some(x),
+            _ => None,
+        })
+        .collect();
+    mvars.sort_unstable();
+    mvars.dedup();
+    mvars
+}
+
+impl Alternation {
+    /// Find an alternation group by comparing the trace of a pattern with its first branch to the
+    /// traces of the same pattern with each other branch. All branches must bind the same
+    /// metavars.
+    pub(crate) fn locate(first: &Trace, others: &[Trace]) -> Option<Self> {
+        let first = &first.buf[..];
+        // the bytes that differ between any two branches
+        let mut prefix = first.len();
+        let mut suffix = first.len();
+        for other in others {
+            let other = &other.buf[..];
+            let shorter = first.len().min(other.len());
+            let p = first.iter().zip(other).take_while(|(x, y)| x == y).count();
+            let s = first.iter().rev().zip(other.iter().rev()).take_while(|(x, y)| x == y).count();
+            if p == shorter && first.len() == other.len() {
+                // identical branches
+                return None;
+            }
+            prefix = prefix.min(p);
+            suffix = suffix.min(s).min(shorter - p);
+        }
+        let (a, b) = (prefix, first.len() - suffix);
+        // widen the difference to a sequence of whole siblings
+        let mut bounds = vec![(0, 0)];
+        let mut syms = Symbols { buf: first };
+        let mut depth = 0usize;
+        while let Some(s) = syms.next() {
+            match s {
+                Symbol::Open => depth += 1,
+                Symbol::Close => depth -= 1,
+                _ => (),
+            }
+            bounds.push((first.len() - syms.buf.len(), depth));
+        }
+        let a = bounds.iter().rev().find(|&&(pos, _)| pos <= a)?.0;
+        let b = bounds.iter().find(|&&(pos, _)| pos >= b)?.0;
+        let within = bounds.iter().filter(|&&(pos, _)| pos >= a && pos <= b);
+        let min_depth = within.map(|&(_, depth)| depth).min()?;
+        let start = bounds.iter().rev().find(|&&(pos, d)| pos <= a && d == min_depth)?.0;
+        let end = bounds.iter().find(|&&(pos, d)| pos >= b && d == min_depth)?.0;
+        let mut branches = vec![first[start..end].to_vec()];
+        for other in others {
+            let other = &other.buf[start..other.buf.len() - (first.len() - end)];
+            if !is_balanced(other) {
+                return None;
+            }
+            branches.push(other.to_vec());
+        }
+        let bound = mvars(&branches[0]);
+        if branches.iter().any(|b| mvars(b) != bound) {
+            return None;
+        }
+        Some(Alternation {
+            start,
+            len: end - start,
+            branches,
+        })
+    }
+}
+
+impl Trace {
+    /// Mark located groups and gaps in the trace they were located in. Groups can be nested in
+    /// repetitions and chains, but can't otherwise overlap.
+    pub(crate) fn with_groups(
+        &self,
+        reps: &[Repetition],
+        alts: &[Alternation],
+        gaps: &[usize],
+        unordered: &[Unordered],
+        chains: &[Chain],
+    ) -> Option<Trace> {
+        // (start, end, opening, closing): a group with a closing has the trace between start and
+        // end between its opening and closing, and groups can be nested in it; the opening of any
+        // other group replaces the trace between start and end
+        type Marked = (usize, usize, Vec<u8>, Option<Vec<u8>>);
+        let mut groups: Vec<Marked> = Vec::new();
+        for r in reps {
+            let mut close = Vec::new();
+            if !r.separator.is_empty() {
+                close.extend_from_slice(&[META, SEPARATOR]);
+                close.extend_from_slice(&r.separator);
+            }
+            close.extend_from_slice(&[META, END]);
+            groups.push((r.start, r.start + r.len, vec![META, REPEAT, r.op], Some(close)));
+        }
+        for a in alts {
+            let mut group = vec![META, ALTERNATE];
+            group.extend_from_slice(&a.branches[0]);
+            for branch in &a.branches[1..] {
+                group.extend_from_slice(&[META, SEPARATOR]);
+                group.extend_from_slice(branch);
+            }
+            group.extend_from_slice(&[META, END]);
+            groups.push((a.start, a.start + a.len, group, None));
+        }
+        for &gap in gaps {
+            groups.push((gap, gap, vec![META, GAP], None));
+        }
+        for u in unordered {
+            groups.push((u.start, u.end, unordered_group(&self.buf[u.start..u.end]), None));
+        }
+        for c in chains {
+            let mut open = vec![META, CHAIN];
+            open.extend_from_slice(&c.call);
+            open.extend_from_slice(&[META, SEPARATOR]);
+            groups.push((c.start, c.end, open, Some(vec![META, END])));
+        }
+        // outer groups before the groups nested in them
+        groups.sort_by_key(|g| (g.0, std::cmp::Reverse(g.1)));
+        let mut buf = Vec::with_capacity(self.buf.len() + groups.len() * 8);
+        let mut pos = 0;
+        // the ends and closings of the groups the position is in
+        let mut open: Vec<(usize, Vec<u8>)> = Vec::new();
+        for (start, end, opening, closing) in groups {
+            while let Some((outer_end, _)) = open.last() {
+                if *outer_end > start {
+                    break;
+                }
+                let (outer_end, closing) = open.pop().unwrap();
+                buf.extend_from_slice(&self.buf[pos..outer_end]);
+                buf.extend_from_slice(&closing);
+                pos = outer_end;
+            }
+            if start < pos || open.last().is_some_and(|(outer_end, _)| end > *outer_end) {
+                return None;
+            }
+            buf.extend_from_slice(&self.buf[pos..start]);
+            buf.extend_from_slice(&opening);
+            match closing {
+                Some(closing) => {
+                    open.push((end, closing));
+                    pos = start;
+                }
+                None => pos = end,
+            }
+        }
+        while let Some((outer_end, closing)) = open.pop() {
+            buf.extend_from_slice(&self.buf[pos..outer_end]);
+            buf.extend_from_slice(&closing);
+            pos = outer_end;
+        }
+        buf.extend_from_slice(&self.buf[pos..]);
+        Some(Trace { buf })
+    }
+
+    /// Make a sequence of statements into a group that matches them in any order, if each is a
+    /// whole subtree or a metavar.
+    pub(crate) fn as_set(&self) -> Option<Trace> {
+        let whole = subtrees(&self.buf).into_iter().all(|item| {
+            matches!(Symbols { buf: item }.next(), Some(Symbol::Open) | Some(Symbol::Meta(_)))
+        });
+        if !whole {
+            return None;
+        }
+        Some(Trace {
+            buf: unordered_group(&self.buf),
+        })
+    }
+
+    /// Require the statements to start the block, and/or end it.
+    pub(crate) fn with_boundaries(&self, start: bool, end: bool) -> Trace {
+        let mut buf = Vec::with_capacity(self.buf.len() + 4);
+        if start {
+            buf.extend_from_slice(&[META, BOUNDARY]);
+        }
+        buf.extend_from_slice(&self.buf);
+        if end {
+            buf.extend_from_slice(&[META, BOUNDARY]);
+        }
+        Trace { buf }
+    }
+
+    /// Mark each occurrence of the given metavars as optional.
+    pub(crate) fn with_optional(&self, mvars: &[u8]) -> Trace {
+        let mut buf = Vec::with_capacity(self.buf.len());
+        let mut syms = self.symbols();
+        loop {
+            let before = syms.buf;
+            let s = match syms.next() {
+                Some(s) => s,
+                None => break,
+            };
+            if let Symbol::Meta(x) = s {
+                if mvars.contains(&x) {
+                    buf.extend_from_slice(&[META, OPTIONAL]);
+                }
+            }
+            buf.extend_from_slice(&before[..before.len() - syms.buf.len()]);
+        }
+        Trace { buf }
+    }
+
+    /// Require the given relations between metavars' bindings.
+    pub(crate) fn with_constraints(&self, constraints: &[Constraint]) -> Trace {
+        let mut buf = Vec::with_capacity(self.buf.len() + constraints.len() * 5);
+        for c in constraints {
+            let rel = if c.equal { b'=' } else { b'!' };
+            buf.extend_from_slice(&[META, CONSTRAINT, c.a, c.b, rel]);
+        }
+        buf.extend_from_slice(&self.buf);
+        Trace { buf }
+    }
+
+    /// Require the values bound by metavars to pass the given tests.
+    pub(crate) fn with_tests(&self, tests: &[ValueTest]) -> Trace {
+        let mut buf = Vec::with_capacity(self.buf.len());
+        for test in tests {
+            let payload = test.encode();
+            assert!(payload.len() <= usize::from(u16::MAX), "test too long");
+            buf.extend_from_slice(&[META, TEST, test.mvar]);
+            buf.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+            buf.extend_from_slice(&payload);
+        }
+        buf.extend_from_slice(&self.buf);
+        Trace { buf }
+    }
+
+    /// Compile the leading value tests.
+    fn tests(&self) -> Vec<Test> {
+        let mut tests = Vec::new();
+        let mut buf = &self.buf[..];
+        while buf.len() >= 2 && buf[0] == META && (buf[1] == TEST || buf[1] == CONSTRAINT) {
+            if buf[1] == CONSTRAINT {
+                buf = &buf[5..];
+                continue;
+            }
+            let len = usize::from(u16::from_le_bytes([buf[3], buf[4]]));
+            tests.push(Test::decode(buf[2], &buf[5..5 + len]));
+            buf = &buf[5 + len..];
+        }
+        tests
+    }
+
+    /// Metavars marked optional, which bind nothing where their subtree is absent.
+    pub fn optional_mvars(&self) -> Vec<u8> {
+        let mut optional = Vec::new();
         let mut syms = self.symbols();
+        while let Some(s) = syms.next() {
+            if let Symbol::Optional = s {
+                match syms.next() {
+                    Some(Symbol::Meta(x)) if !optional.contains(&x) => optional.push(x),
+                    _ => (),
+                }
+            }
+        }
+        optional
+    }
+
+    /// Metavars that occur inside a repetition group, and so bind once per repetition.
+    pub fn repeated_mvars(&self) -> Vec<u8> {
+        let mut repeated = Vec::new();
+        // for each open group, whether it is a repetition
+        let mut groups = Vec::new();
+        for s in self.symbols() {
+            match s {
+                Symbol::Repeat(_) => groups.push(true),
+                s if opens_group(&s) => groups.push(false),
+                Symbol::End => {
+                    groups.pop();
+                }
+                Symbol::Meta(x) if groups.contains(&true) && x != WILDCARD && !repeated.contains(&x) => {
+                    repeated.push(x)
+                }
+                _ => (),
+            }
+        }
+        repeated
+    }
+
+    /// The length of the shortest input a pattern could match.
+    fn min_match_len(&self) -> usize {
         let mut n = 0;
+        let mut syms = self.symbols();
         while let Some(s) = syms.next() {
-            if let Symbol::Open = s {
-                n += 1;
-                close_subtree(&mut syms);
+            n += match s {
+                Symbol::Literal(META) => 2,
+                Symbol::Literal(_) => 1,
+                Symbol::Open | Symbol::Close => 2,
+                // an empty subtree
+                Symbol::Meta(_) => 4,
+                Symbol::Repeat(b'+') => 0,
+                Symbol::Repeat(_) => {
+                    // optional contents
+                    skip_group(&mut syms);
+                    0
+                }
+                // separators are only required between repetitions
+                Symbol::Separator => {
+                    skip_group(&mut syms);
+                    0
+                }
+                Symbol::End => 0,
+                Symbol::Optional => {
+                    syms.next();
+                    0
+                }
+                // (as if any branch could be empty)
+                Symbol::Alternate | Symbol::Unordered | Symbol::Chain => {
+                    skip_group(&mut syms);
+                    0
+                }
+                Symbol::Constraint(_) | Symbol::Test(_) | Symbol::Gap | Symbol::Boundary => 0,
             }
         }
         n
@@ -483,14 +1379,39 @@ impl Trace {
     pub fn is_match(&self, input: &Trace) -> bool {
         // performance: parsing symbols is probably slow. Faster to compare optimistically, and
         // backtrack 1 byte on mismatch to check meta status
-        is_match(self.symbols(), &mut input.symbols())
+        match_prefix(self.symbols(), &self.tests(), input.symbols(), Vec::new()).is_some()
+    }
+
+    /// Search a scope of the input around a match for this pattern, at any depth, with any
+    /// metavars it shares with the matched pattern already bound as they were there.
+    pub fn occurs(&self, input: &IndexedTrace, site: &Site, scope: Scope) -> bool {
+        let span = site.span(input, scope);
+        let bindings: Vec<_> = site.bindings.iter().map(|b| b.as_deref()).collect();
+        let tests = self.tests();
+        let mut input = Symbols {
+            buf: &input.trace.buf[span],
+        };
+        while !input.buf.is_empty() {
+            if match_prefix(self.symbols(), &tests, input.clone(), bindings.clone()).is_some() {
+                return true;
+            }
+            input.next();
+        }
+        false
     }
 
     /// Return iterator of top-level matches
     pub fn toplevel_matches<'s, 'i>(&'s self, input: &'i IndexedTrace) -> ToplevelMatches<'s, 'i> {
+        // a match after a leading gap's first statement would be part of the match from there
+        let first = self
+            .symbols()
+            .find(|s| !matches!(s, Symbol::Constraint(_) | Symbol::Test(_)));
+        let from_start = matches!(first, Some(Symbol::Boundary) | Some(Symbol::Gap));
         ToplevelMatches {
             pattern: self.symbols(),
+            tests: self.tests(),
             inputs: input.traces(),
+            from_start,
         }
     }
 
@@ -499,23 +1420,27 @@ impl Trace {
     pub fn internal_matches<'s, 'i>(&'s self, input: &'i IndexedTrace) -> InternalMatches<'s, 'i> {
         let in_len = input.trace.buf.len();
         let input = input.trace.symbols();
-        InternalMatches::new(self.symbols(), input, in_len)
+        InternalMatches::new(self, input, in_len)
     }
 }
 
 pub struct InternalMatches<'p, 'i> {
     pattern: Symbols<'p>,
+    tests: Vec<Test>,
+    min_len: usize,
     orig_input: Symbols<'i>,
     input: Symbols<'i>,
     in_len: usize,
 }
 
 impl<'p, 'i> InternalMatches<'p, 'i> {
-    fn new(pattern: Symbols<'p>, input: Symbols<'i>, in_len: usize) -> Self {
+    fn new(pattern: &'p Trace, input: Symbols<'i>, in_len: usize) -> Self {
         // Degenerate case, should prevent at pattern compilation time.
         assert!(!pattern.buf.is_empty());
         InternalMatches {
-            pattern,
+            pattern: pattern.symbols(),
+            tests: pattern.tests(),
+            min_len: pattern.min_match_len(),
             orig_input: input.clone(),
             input,
             in_len,
@@ -524,25 +1449,27 @@ impl<'p, 'i> InternalMatches<'p, 'i> {
 }
 
 impl<'p, 'i> Iterator for InternalMatches<'p, 'i> {
-    type Item = Trace;
+    /// The reconstructed input, the pattern as it matched, and the site.
+    type Item = (Trace, Trace, Site);
 
     fn next(&mut self) -> Option<Self::Item> {
-        // A pattern can't match anything shorter than itself.
-        while self.input.buf.len() >= self.pattern.buf.len() {
+        // A pattern can't match anything shorter than its shortest instance.
+        while self.input.buf.len() >= self.min_len && !self.input.buf.is_empty() {
             let remaining = self.input.buf.len();
-            let mut here = self.input.clone();
+            let here = self.input.clone();
             // Consume a symbol.
             self.input.next().unwrap();
             // Performance note in is_match applies times N here.
-            if is_match(self.pattern.clone(), &mut here) {
+            if let Some(found) = match_prefix(self.pattern.clone(), &self.tests, here, Vec::new()) {
                 let pos = self.in_len - remaining;
                 let mut tracer = Tracer::default();
                 tracer.buf.extend_from_slice(&self.orig_input.buf[..pos]);
                 tracer.push_mvar(1);
-                tracer.buf.extend_from_slice(&here.buf);
+                tracer.buf.extend_from_slice(found.rest.buf);
                 let trace = tracer.finish();
                 trace!("reconstructed: {:?}", trace);
-                return Some(trace);
+                let site = found.site(pos..self.in_len - found.rest.buf.len());
+                return Some((trace, found.matched, site));
             }
         }
         None
@@ -565,42 +1492,491 @@ fn close_subtree(syms: &mut Symbols) {
 
 const CLOSER_LEN: usize = 2;
 
-// NB. for efficiency, caller should handle quitting when input is shorter than pattern
-fn is_match(pattern: Symbols, input: &mut Symbols) -> bool {
-    //trace!("is_match:\n\tptn: {:?}\n\tinp: {:?}", pattern.clone(), input.clone());
-    let mut bindings = Vec::new();
-    for p in pattern {
-        match (p, input.next()) {
-            (ref x, Some(ref y)) if x == y => (),
-            (Symbol::Meta(x), Some(Symbol::Open)) => {
-                let x = x as usize;
-                if x >= bindings.len() {
-                    bindings.resize(x + 1, None);
+/// A match of a pattern against the beginning of some input.
+struct Prefix<'i> {
+    /// The unmatched remainder of the input.
+    rest: Symbols<'i>,
+    /// The pattern as it matched: with repetitions unrolled, gaps filled by wildcards, and
+    /// metavars that didn't bind anything new replaced by wildcards.
+    matched: Trace,
+    /// Where in `matched` each top-level gap ended, and each statement of a top-level set began.
+    anchors: Vec<usize>,
+    bindings: Vec<Option<&'i [u8]>>,
+}
+
+impl Prefix<'_> {
+    fn site(&self, span: Range<usize>) -> Site {
+        Site {
+            span,
+            bindings: self.bindings.iter().map(|b| b.map(<[u8]>::to_vec)).collect(),
+        }
+    }
+}
+
+/// Match a pattern against the beginning of the input, with some metavars possibly bound already.
+fn match_prefix<'i>(
+    mut pattern: Symbols,
+    tests: &[Test],
+    input: Symbols<'i>,
+    bindings: Vec<Option<&'i [u8]>>,
+) -> Option<Prefix<'i>> {
+    //trace!("match_prefix:\n\tptn: {:?}\n\tinp: {:?}", pattern.clone(), input.clone());
+    let mut constraints = Vec::new();
+    loop {
+        match pattern.clone().next() {
+            Some(Symbol::Constraint(c)) => constraints.push(c),
+            Some(Symbol::Test(_)) => (),
+            // the start of the block, which is up to the caller
+            Some(Symbol::Boundary) => (),
+            _ => break,
+        }
+        pattern.next();
+    }
+    let mut matcher = Matcher {
+        bindings,
+        constraints,
+        tests,
+        matched: Tracer::default(),
+        anchors: Vec::new(),
+    };
+    let mut rest = None;
+    if matcher.seq(pattern, input, &mut |_, input| {
+        rest = Some(input);
+        true
+    }) {
+        Some(Prefix {
+            rest: rest.unwrap(),
+            matched: matcher.matched.finish(),
+            anchors: matcher.anchors,
+            bindings: matcher.bindings,
+        })
+    } else {
+        None
+    }
+}
+
+/// What to match after a successful submatch. Returns whether the whole match succeeded.
+type Continuation<'k, 'p, 'i> = &'k mut dyn FnMut(&mut Matcher<'p, 'i>, Symbols<'i>) -> bool;
+
+/// A repetition group in a pattern.
+struct Repeat<'p> {
+    op: u8,
+    body: Symbols<'p>,
+    separator: Symbols<'p>,
+    // metavars that get new bindings in each repetition
+    fresh: Vec<usize>,
+}
+
+impl Repeat<'_> {
+    fn max(&self) -> usize {
+        if self.op == b'?' {
+            1
+        } else {
+            usize::MAX
+        }
+    }
+
+    fn min(&self) -> usize {
+        if self.op == b'+' {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+/// Whether a symbol opens a group that is closed by an `End`.
+fn opens_group(s: &Symbol) -> bool {
+    matches!(s, Symbol::Repeat(_) | Symbol::Alternate | Symbol::Unordered | Symbol::Chain)
+}
+
+/// Consume the rest of a group, with any groups nested in it.
+fn skip_group(syms: &mut Symbols) {
+    let mut depth = 0;
+    for s in syms.by_ref() {
+        match s {
+            Symbol::End if depth == 0 => return,
+            Symbol::End => depth -= 1,
+            s if opens_group(&s) => depth += 1,
+            _ => (),
+        }
+    }
+}
+
+/// Split a group into its separated parts, consuming the pattern up to the group's end.
+fn split_group<'p>(pattern: &mut Symbols<'p>) -> Vec<Symbols<'p>> {
+    let mut parts = Vec::new();
+    let mut start = pattern.buf;
+    // groups nested in the part
+    let mut depth = 0;
+    loop {
+        let before = pattern.buf;
+        match pattern.next().expect("groups are closed") {
+            Symbol::End if depth > 0 => depth -= 1,
+            s if opens_group(&s) => depth += 1,
+            s @ Symbol::Separator | s @ Symbol::End if depth == 0 => {
+                parts.push(Symbols {
+                    buf: &start[..start.len() - before.len()],
+                });
+                if s == Symbol::End {
+                    return parts;
+                }
+                start = pattern.buf;
+            }
+            _ => (),
+        }
+    }
+}
+
+/// The state of one match attempt. When a submatch fails, its caller is responsible for restoring
+/// any state it needs.
+struct Matcher<'p, 'i> {
+    bindings: Vec<Option<&'i [u8]>>,
+    constraints: Vec<Constraint>,
+    tests: &'p [Test],
+    matched: Tracer,
+    // the length of the matched trace at the end of each top-level gap so far, and at the start
+    // of each statement matched by a top-level set
+    anchors: Vec<usize>,
+}
+
+impl<'p, 'i> Matcher<'p, 'i> {
+    /// Match `pattern` against the beginning of `input`, then match the continuation against the
+    /// rest.
+    fn seq(&mut self, mut pattern: Symbols, mut input: Symbols<'i>, k: Continuation<'_, 'p, 'i>) -> bool {
+        while let Some(p) = pattern.next() {
+            match p {
+                Symbol::Repeat(op) => {
+                    let mut parts = split_group(&mut pattern).into_iter();
+                    let body = parts.next().unwrap();
+                    let separator = parts.next().unwrap_or(Symbols { buf: &[] });
+                    let fresh = body
+                        .clone()
+                        .filter_map(|s| match s {
+                            Symbol::Meta(x) if x != WILDCARD => Some(usize::from(x)),
+                            _ => None,
+                        })
+                        .filter(|&x| !matches!(self.bindings.get(x), Some(Some(_))))
+                        .collect();
+                    let rep = Repeat {
+                        op,
+                        body,
+                        separator,
+                        fresh,
+                    };
+                    return self.repeat(&rep, 0, input, &mut |m, input| {
+                        m.seq(pattern.clone(), input, k)
+                    });
+                }
+                Symbol::Alternate => {
+                    for branch in split_group(&mut pattern) {
+                        let (bindings, matched_len) = (self.bindings.clone(), self.matched.buf.len());
+                        if self.seq(branch, input.clone(), &mut |m, input| {
+                            m.seq(pattern.clone(), input, &mut *k)
+                        }) {
+                            return true;
+                        }
+                        self.bindings = bindings;
+                        self.matched.buf.truncate(matched_len);
+                    }
+                    return false;
+                }
+                Symbol::Optional => {
+                    let x = match pattern.next() {
+                        Some(Symbol::Meta(x)) => x,
+                        _ => unreachable!("only metavars are optional"),
+                    };
+                    // prefer the subtree present, falling back to absent
+                    let mut present = input.clone();
+                    if let Some(Symbol::Open) = present.next() {
+                        let (bindings, matched_len) = (self.bindings.clone(), self.matched.buf.len());
+                        if self.bind(x, &mut present) && self.seq(pattern.clone(), present, &mut *k) {
+                            return true;
+                        }
+                        self.bindings = bindings;
+                        self.matched.buf.truncate(matched_len);
+                    }
+                }
+                Symbol::Gap => {
+                    // prefer skipping as few siblings as possible
+                    let (bindings, matched_len) = (self.bindings.clone(), self.matched.buf.len());
+                    let toplevel = is_balanced(&self.matched.buf);
+                    loop {
+                        let skipped_len = self.matched.buf.len();
+                        if toplevel {
+                            self.anchors.push(skipped_len);
+                        }
+                        if self.seq(pattern.clone(), input.clone(), &mut *k) {
+                            return true;
+                        }
+                        if toplevel {
+                            self.anchors.pop();
+                        }
+                        self.bindings.clone_from(&bindings);
+                        self.matched.buf.truncate(skipped_len);
+                        match input.next() {
+                            Some(Symbol::Open) => close_subtree(&mut input),
+                            _ => {
+                                self.matched.buf.truncate(matched_len);
+                                return false;
+                            }
+                        }
+                        self.matched.push_mvar(WILDCARD);
+                    }
+                }
+                Symbol::Unordered => {
+                    let items: Vec<_> = split_group(&mut pattern)
+                        .into_iter()
+                        .filter(|item| !item.buf.is_empty())
+                        .collect();
+                    // the siblings remaining in the current subtree
+                    let mut siblings = Vec::new();
+                    loop {
+                        let mut rest = input.clone();
+                        if rest.next() != Some(Symbol::Open) {
+                            break;
+                        }
+                        close_subtree(&mut rest);
+                        let len = input.buf.len() - rest.buf.len();
+                        siblings.push(Symbols {
+                            buf: &input.buf[..len],
+                        });
+                        input = rest;
+                    }
+                    // a group that begins a top-level match is a set of statements, which must
+                    // include the statement the match begins at
+                    let anchored = self.matched.buf.is_empty();
+                    let mut fragments = vec![None; siblings.len()];
+                    let k = &mut |m: &mut Self, input| m.seq(pattern.clone(), input, &mut *k);
+                    return self.unordered(&items, &siblings, &mut fragments, anchored, input, k);
                 }
-                if let Some(matched) = bindings[x] {
-                    if !input.buf.starts_with(matched) {
-                        //trace!("is_match: false; binding differed: {}", x);
+                Symbol::Chain => {
+                    let mut parts = split_group(&mut pattern).into_iter();
+                    let (call, receiver) = (parts.next().unwrap(), parts.next().unwrap());
+                    return self.chain(&call, &receiver, input, &mut |m, input| {
+                        m.seq(pattern.clone(), input, &mut *k)
+                    });
+                }
+                Symbol::Boundary => {
+                    // the end of the block: no more siblings
+                    if let Some(Symbol::Open) | Some(Symbol::Meta(_)) = input.clone().next() {
                         return false;
                     }
-                    //trace!("is_match: binding rematched: {}", x);
-                    let (_, rest) = input.buf.split_at(matched.len() + CLOSER_LEN);
-                    input.buf = rest;
-                } else {
-                    // consume the subtree and remember its dimensions
-                    //trace!("is_match: bound a binding: {}", x);
-                    let (from_match, from_match_len) = (input.clone(), input.buf.len());
-                    close_subtree(input);
-                    let subtree_len = from_match_len - input.buf.len() - CLOSER_LEN;
-                    let (matched, _) = from_match.buf.split_at(subtree_len);
-                    bindings[x] = Some(matched);
                 }
+                Symbol::Meta(x) => match input.next() {
+                    Some(Symbol::Open) => {
+                        if !self.bind(x, &mut input) {
+                            return false;
+                        }
+                    }
+                    _ => return false,
+                },
+                p => {
+                    if input.next().as_ref() != Some(&p) {
+                        //trace!("seq: false; structure differed {:02x?} // {:02x?}", p, input);
+                        return false;
+                    }
+                    match p {
+                        Symbol::Literal(x) => self.matched.push_byte(x),
+                        Symbol::Open => self.matched.open_subtree(),
+                        Symbol::Close => self.matched.close_subtree(),
+                        _ => unreachable!("pattern is well-formed"),
+                    }
+                }
+            }
+        }
+        k(self, input)
+    }
+
+    /// Match one more repetition if possible, falling back to matching the continuation.
+    fn repeat(&mut self, rep: &Repeat, n: usize, input: Symbols<'i>, k: Continuation<'_, 'p, 'i>) -> bool {
+        if n < rep.max() {
+            let (bindings, matched_len) = (self.bindings.clone(), self.matched.buf.len());
+            self.unbind(&rep.fresh);
+            let start = input.buf.len();
+            let separator = if n == 0 {
+                Symbols { buf: &[] }
+            } else {
+                rep.separator.clone()
+            };
+            if self.seq(separator, input.clone(), &mut |m, input| {
+                m.seq(rep.body.clone(), input, &mut |m, input| {
+                    // a repetition that matches nothing could repeat forever
+                    input.buf.len() < start && m.repeat(rep, n + 1, input, &mut *k)
+                })
+            }) {
+                return true;
+            }
+            self.bindings = bindings;
+            self.matched.buf.truncate(matched_len);
+        }
+        if n < rep.min() {
+            return false;
+        }
+        self.unbind(&rep.fresh);
+        k(self, input)
+    }
+
+    /// Match each item to a distinct sibling, then the continuation against the rest of the input.
+    /// The matched trace has each sibling in order: as its item matched, or a wildcard. If the
+    /// group is anchored, the first sibling must be matched, and each matched sibling is noted.
+    fn unordered(
+        &mut self,
+        items: &[Symbols],
+        siblings: &[Symbols<'i>],
+        fragments: &mut Vec<Option<Vec<u8>>>,
+        anchored: bool,
+        rest: Symbols<'i>,
+        k: Continuation<'_, 'p, 'i>,
+    ) -> bool {
+        let (item, items) = match items.split_first() {
+            Some(x) => x,
+            None => {
+                if anchored && matches!(fragments.first(), Some(None)) {
+                    return false;
+                }
+                let (matched_len, anchors_len) = (self.matched.buf.len(), self.anchors.len());
+                for fragment in fragments.iter() {
+                    match fragment {
+                        Some(fragment) => {
+                            if anchored {
+                                self.anchors.push(self.matched.buf.len());
+                            }
+                            self.matched.buf.extend_from_slice(fragment);
+                        }
+                        None => self.matched.push_mvar(WILDCARD),
+                    }
+                }
+                if k(self, rest) {
+                    return true;
+                }
+                self.matched.buf.truncate(matched_len);
+                self.anchors.truncate(anchors_len);
+                return false;
+            }
+        };
+        for (j, sibling) in siblings.iter().enumerate() {
+            if fragments[j].is_some() {
+                continue;
+            }
+            let (bindings, matched_len) = (self.bindings.clone(), self.matched.buf.len());
+            if self.seq(item.clone(), sibling.clone(), &mut |m, input| {
+                if !input.buf.is_empty() {
+                    return false;
+                }
+                // set the item's trace aside until every item has matched
+                let fragment = m.matched.buf.split_off(matched_len);
+                fragments[j] = Some(fragment);
+                if m.unordered(items, siblings, fragments, anchored, rest.clone(), &mut *k) {
+                    return true;
+                }
+                let fragment = fragments[j].take().unwrap();
+                m.matched.buf.extend_from_slice(&fragment);
+                false
+            }) {
+                return true;
+            }
+            self.bindings = bindings;
+            self.matched.buf.truncate(matched_len);
+        }
+        false
+    }
+
+    /// Match the receiver, or a method call whose receiver matches this recursively, then the
+    /// continuation against the rest of the input.
+    fn chain(&mut self, call: &Symbols, receiver: &Symbols, input: Symbols<'i>, k: Continuation<'_, 'p, 'i>) -> bool {
+        // prefer as few calls as possible
+        let (bindings, matched_len) = (self.bindings.clone(), self.matched.buf.len());
+        if self.seq(receiver.clone(), input.clone(), &mut *k) {
+            return true;
+        }
+        self.bindings.clone_from(&bindings);
+        self.matched.buf.truncate(matched_len);
+        if self.seq(call.clone(), input, &mut |m, input| {
+            m.chain(call, receiver, input, &mut |m, mut input| {
+                // the rest of the call, as it is
+                let rest = input.buf;
+                close_subtree(&mut input);
+                let len = rest.len() - input.buf.len() - CLOSER_LEN;
+                m.matched.buf.extend_from_slice(&rest[..len]);
+                m.matched.close_subtree();
+                k(m, input)
+            })
+        }) {
+            return true;
+        }
+        self.bindings = bindings;
+        self.matched.buf.truncate(matched_len);
+        false
+    }
+
+    fn unbind(&mut self, mvars: &[usize]) {
+        for &x in mvars {
+            if let Some(b) = self.bindings.get_mut(x) {
+                *b = None;
             }
-            (ref _x, ref _y) => {
-                //trace!("is_match: false; structure differed {:02x?} // {:02x?}", _x, _y);
+        }
+    }
+
+    /// Check a new binding against its value tests, and the constraints relating it to metavars
+    /// already bound.
+    fn satisfies(&self, x: u8, matched: &[u8]) -> bool {
+        let passed = self
+            .tests
+            .iter()
+            .filter(|test| test.mvar == x)
+            .all(|test| test.passes(matched));
+        passed && self.constraints.iter().all(|c| {
+            let other = if c.a == x {
+                c.b
+            } else if c.b == x {
+                c.a
+            } else {
+                return true;
+            };
+            match self.bindings.get(usize::from(other)) {
+                Some(Some(bound)) => c.holds(matched, bound),
+                _ => true,
+            }
+        })
+    }
+
+    /// Match a metavar against the subtree that has just been opened.
+    fn bind(&mut self, x: u8, input: &mut Symbols<'i>) -> bool {
+        if x == WILDCARD {
+            close_subtree(input);
+            self.matched.push_mvar(WILDCARD);
+            return true;
+        }
+        let x = usize::from(x);
+        if x >= self.bindings.len() {
+            self.bindings.resize(x + 1, None);
+        }
+        if let Some(matched) = self.bindings[x] {
+            if !input.buf.starts_with(matched)
+                || !input.buf[matched.len()..].starts_with(&[META, CLOSE])
+            {
+                //trace!("bind: false; binding differed: {}", x);
+                return false;
+            }
+            //trace!("bind: binding rematched: {}", x);
+            let (_, rest) = input.buf.split_at(matched.len() + CLOSER_LEN);
+            input.buf = rest;
+            // only the first occurrence is bound
+            self.matched.push_mvar(WILDCARD);
+        } else {
+            // consume the subtree and remember its dimensions
+            //trace!("bind: bound a binding: {}", x);
+            let (from_match, from_match_len) = (input.clone(), input.buf.len());
+            close_subtree(input);
+            let subtree_len = from_match_len - input.buf.len() - CLOSER_LEN;
+            let (matched, _) = from_match.buf.split_at(subtree_len);
+            if !self.satisfies(x as u8, matched) {
                 return false;
             }
+            self.bindings[x] = Some(matched);
+            self.matched.push_mvar(x as u8);
         }
+        true
     }
-    //trace!("is_match: true!");
-    true
 }
@@ -0,0 +1,537 @@
+use comacro::ast::{Binding, Enclosing, Input, Match, Pattern, PatternDef};
+
+/// Parse a pattern written as a `macro name(args) { body }` item.
+fn parse(pattern: &str) -> comacro::ast::Result<PatternDef> {
+    match syn::parse_str::<syn::Item>(pattern).expect("pattern item") {
+        syn::Item::Macro2(mac) => PatternDef::parse(mac.args, mac.body),
+        _ => panic!("not a macro item"),
+    }
+}
+
+/// All matches of a pattern in the bodies of an input file's fns and methods.
+fn matches(pattern: &str, input: &str) -> Vec<Match> {
+    find(&parse(pattern).expect("pattern parses").compile(), input)
+}
+
+fn find(pattern: &Pattern, input: &str) -> Vec<Match> {
+    let file = syn::parse_file(input).expect("input parses");
+    let mut found = Vec::new();
+    let mut find = |stmts, items: &[Enclosing]| {
+        let input = Input { stmts };
+        let trace = input.compile();
+        found.extend(pattern.matches(&input.stmts, &trace).within(items));
+    };
+    for item in file.items {
+        match item {
+            syn::Item::Fn(f) => {
+                let items = [Enclosing::of_fn(&f)];
+                find(f.block.stmts, &items);
+            }
+            syn::Item::Impl(i) => {
+                let imp = Enclosing::of_impl(&i);
+                for item in i.items {
+                    if let syn::ImplItem::Method(m) = item {
+                        let items = [imp.clone(), Enclosing::of_method(&m)];
+                        find(m.block.stmts, &items);
+                    }
+                }
+            }
+            _ => panic!("non-fn item in input"),
+        }
+    }
+    found
+}
+
+fn count(pattern: &str, input: &str) -> usize {
+    matches(pattern, input).len()
+}
+
+#[test]
+fn struct_pattern_rest() {
+    let input = "fn f() {
+        match y { Foo { a, .. } => 1, _ => 2 };
+        let Foo { a, .. } = y;
+        if let Foo { a, .. } = y {}
+        let Foo { a, b } = y;
+    }";
+    let arms = "macro m($x: expr) { match $x { Foo { a, .. } => 1, _ => 2 } }";
+    assert_eq!(count(arms, input), 1);
+    assert_eq!(count("macro m($x: expr) { let Foo { a, .. } = $x; }", input), 1);
+    assert_eq!(count("macro m($x: expr) { if let Foo { a, .. } = $x {} }", input), 1);
+}
+
+#[test]
+fn range_full_argument() {
+    let input = "fn f() { y.get(..); y.get(1); }";
+    assert_eq!(count("macro m($x: expr) { $x.get(..) }", input), 1);
+}
+
+#[test]
+fn argument_gap() {
+    let input = "fn f() {
+        HashMap::with_capacity_and_hasher(4, Default::default());
+        HashMap::with_capacity_and_hasher(4);
+        HashMap::with_capacity(4, s);
+    }";
+    let pattern = "macro m($n: expr) { HashMap::with_capacity_and_hasher($n, ..) }";
+    assert_eq!(count(pattern, input), 2);
+}
+
+#[test]
+fn field_gap() {
+    let input = "fn f() {
+        Foo { cap: 1, len: 0 };
+        Foo { len: 0 };
+        Foo { len: 1, cap: 0 };
+    }";
+    assert_eq!(count("macro m() { Foo { len: 0, .. } }", input), 2);
+}
+
+#[test]
+fn macro_invocation() {
+    let input = "fn f() { assert_eq!(x, true); assert_eq!(x, false); vec![0; n]; }";
+    assert_eq!(count("macro m($a: tt) { assert_eq!($a, true) }", input), 1);
+    assert_eq!(count("macro m($a: ident) { assert_eq!($a, false) }", input), 1);
+    assert_eq!(count("macro m($l: tt, $n: ident) { vec![$l; $n] }", input), 1);}
+
+#[test]
+fn tt_metavar_is_one_token_tree() {
+    let pattern = "macro m($a: tt, $b: tt) { assert_eq!($a, $b) }";
+    assert_eq!(count(pattern, "fn f() { assert_eq!(n, 0); }"), 1);
+    assert_eq!(count(pattern, "fn f() { assert_eq!(x.len(), 0); }"), 0);
+}
+
+#[test]
+fn macro_invocation_rejects_other_kinds() {
+    assert!(parse("macro m($a: expr) { assert_eq!($a, true) }").is_err());
+    assert!(parse("macro m($l: lit, $n: expr) { vec![$l; $n] }").is_err());
+}
+
+#[test]
+fn type_metavar() {
+    let input = "fn f() { let a: u8 = 1; let b = 1; foo(u8); c as u32; }";
+    assert_eq!(count("macro m($x: ident, $t: ty, $e: expr) { let $x: $t = $e; }", input), 1);
+    assert_eq!(count("macro m($e: expr, $t: ty) { $e as $t }", input), 1);
+}
+
+#[test]
+fn type_metavar_in_expression_position() {
+    assert!(parse("macro m($t: ty) { foo($t) }").is_err());
+}
+
+#[test]
+fn path_metavar() {
+    let input = "fn f() { std::mem::drop(x); Vec::<u8>::new(); ::std::process::exit(1); drop(y) }";
+    assert_eq!(count("macro m($f: path, $x: expr) { $f($x) }", input), 3);
+    assert_eq!(count("macro m($p: path) { $p() }", input), 1);
+}
+
+#[test]
+fn path_metavar_out_of_place() {
+    assert!(parse("macro m($x: path) { let $x = 1; }").is_err());
+    assert!(parse("macro m($x: expr, $p: path) { $x.$p() }").is_err());
+    assert!(parse("macro m($p: path) { $p::new() }").is_err());
+}
+
+#[test]
+fn stmt_metavar() {
+    let input = "fn f() { let t = x; foo(t); drop(t); let u = y; drop(u); }";
+    assert_eq!(count("macro m($t: ident, $x: expr, $s: stmt) { let $t = $x; $s; drop($t); }", input), 1);
+    assert_eq!(count("macro m($s: stmt) { $s }", input), 5);
+}
+
+#[test]
+fn stmt_metavar_out_of_place() {
+    assert!(parse("macro m($s: stmt) { foo($s) }").is_err());
+}
+
+#[test]
+fn repetition_after_same_metavar() {
+    let pattern = "macro m($x: expr) { foo($x, $($x),*) }";
+    assert_eq!(count(pattern, "fn f() { foo(a, a, a); }"), 1);
+    assert_eq!(count(pattern, "fn f() { foo(a); }"), 1);
+    assert_eq!(count(pattern, "fn f() { foo(a, b); }"), 0);
+    assert_eq!(count(pattern, "fn f() { foo(a, a, b); }"), 0);
+}
+
+#[test]
+fn nested_repetition() {
+    assert!(parse("macro m($x: expr) { $( foo($( $x ),*); )* }").is_err());
+    assert!(parse("macro m($x: expr) { $( foo($x); )* }").is_ok());
+}
+
+#[test]
+fn chain_gap() {
+    let input = "fn f() {
+        let b = m.get(1).cloned().unwrap();
+        let c = m.get(k).map(|x| x + 1).cloned().unwrap();
+        let f = m.get(2).unwrap();
+        let g = m.get(2).expect(\"\");
+    }";
+    assert_eq!(count("macro m($m: expr, $k: expr) { $m.get($k). ... .unwrap() }", input), 3);
+}
+
+#[test]
+fn two_chain_gaps() {
+    let input = "fn f() {
+        v.iter().map(g).count();
+        v.iter().cloned().map(g).filter(h).count();
+        v.iter().filter(h).count();
+        v.into_iter().map(g).count();
+    }";
+    let pattern = "macro m($it: expr, $f: expr) { $it.iter(). ... .map($f). ... .count() }";
+    assert_eq!(count(pattern, input), 2);
+}
+
+#[test]
+fn match_without_wildcard_arm() {
+    let input = "fn f() {
+        match x { 1 => a, _ => b };
+        match y { 1 => a, 2 => b };
+        let _ = z;
+    }";
+    let arm = "macro m($e: expr) { match $e { $($p: pat => $b: expr),* } where not within { _ => $_ } }";
+    assert_eq!(count(arm, input), 1);
+    let pat = "macro m($e: expr) { match $e { $($p: pat => $b: expr),* } where not within { _ } }";
+    assert_eq!(count(pat, input), 1);
+    let whole = "macro m($e: expr) {
+        match $e { $($p: pat => $b: expr),* } where not within { match $e { _ => $d: expr } }
+    }";
+    assert_eq!(count(whole, input), 2);
+}
+
+#[test]
+fn unused_binding() {
+    let input = "fn f() { let a = 1; foo(a); let b = 2; bar(); }";
+    assert_eq!(count("macro m($x: ident, $e: expr) { let $x = $e; where not after { $x } }", input), 1);
+}
+
+#[test]
+fn predicate() {
+    let input = "fn f() { foo(bar(1)); foo(baz(1)); foo(2); }";
+    let pattern = parse("macro m($x: expr) { foo($x) }").unwrap().compile();
+    let is_call = |b: &Binding| matches!(b, Binding::Expr(syn::Expr::Call(_)));
+    let pattern = pattern.with_predicate("x", is_call).unwrap();
+    assert_eq!(find(&pattern, input).len(), 2);
+}
+
+#[test]
+fn predicate_on_unknown_metavar() {
+    let pattern = parse("macro m($x: expr) { foo($x) }").unwrap().compile();
+    assert!(pattern.with_predicate("y", |_: &Binding| true).is_err());
+}
+
+#[test]
+fn predicate_on_negated_metavar() {
+    // the negated metavar is declared before the bound one, so it has an unbound slot
+    let pattern = "macro m($y: expr, $x: ident) { let $x = 1; where not after { foo($y) } }";
+    let pattern = parse(pattern).unwrap().compile();
+    let pattern = pattern.with_predicate("y", |_: &Binding| false).unwrap();
+    let input = "fn f() { let a = 1; bar(a); let b = 1; }";
+    assert_eq!(find(&pattern, input).len(), 2);
+    let named_a = |b: &Binding| matches!(b, Binding::Ident(x) if *x == "a");
+    let pattern = pattern.with_predicate("x", named_a).unwrap();
+    assert_eq!(find(&pattern, input).len(), 1);
+}
+
+#[test]
+fn statements_in_nested_blocks() {
+    let input = "fn f() { if c { let t = x; drop(t); } loop { let u = y; foo(u); } }";
+    let found = matches("macro m($t: ident, $x: expr) { let $t = $x; drop($t); }", input);
+    assert_eq!(found.len(), 1);
+    assert_eq!(count("macro m($t: ident, $x: expr) { let $t = $x; bar($t); }", input), 0);
+}
+
+#[test]
+fn leading_gap_matches_once() {
+    let input = "fn f() { a(); b(); c(); }";
+    assert_eq!(count("macro m() { ...; c(); }", input), 1);
+    assert_eq!(count("macro m() { ...; d(); }", input), 0);
+}
+
+#[test]
+fn statements_inside_loop() {
+    let pattern = "macro m($x: expr) { foo($x); where inside loop }";
+    assert_eq!(count(pattern, "fn f() { loop { foo(2); } }"), 1);
+    assert_eq!(count(pattern, "fn f() { foo(2); if c { foo(3); } }"), 0);
+    let pattern = "macro m($x: expr) { foo($x); where not inside loop }";
+    assert_eq!(count(pattern, "fn f() { foo(1); while c { foo(2); } }"), 1);
+}
+
+#[test]
+fn malformed_where_clause() {
+    assert!(parse("macro m($x: lit) { foo($x) where $x == 1 }").is_ok());
+    assert!(parse("macro m($x: lit) { foo($x) where $y == 1 }").is_err());
+    assert!(parse("macro m($x: lit) { foo($x) where $x + 1 }").is_err());
+    assert!(parse("macro m($x: lit) { foo($x) where $x == bar }").is_err());
+    assert!(parse("macro m($x: lit) { foo($x) where $x == $x }").is_err());
+    assert!(parse("macro m($x: lit) { foo($x) where not $x == 1 }").is_err());
+    assert!(parse("macro m($x: lit) { foo($x) where inside elsewhere }").is_err());
+    assert!(parse("macro m($x: lit) { foo($x) where $x used after == y }").is_err());
+}
+
+#[test]
+fn int_relations() {
+    let input = "fn f() { foo(0); foo(3); foo(9); }";
+    assert_eq!(count("macro m($n: lit) { foo($n) where $n in 1..=9 }", input), 2);
+    assert_eq!(count("macro m($n: lit) { foo($n) where $n < 3 }", input), 1);
+    assert_eq!(count("macro m($n: lit) { foo($n) where $n > 9 }", input), 0);
+}
+
+#[test]
+fn invalid_int_relations() {
+    assert!(parse("macro m($n: expr) { foo($n) where $n == 0 }").is_err());
+    assert!(parse("macro m($n: expr) { foo($n) where $n in 0..2 }").is_err());
+    assert!(parse("macro m($n: lit) { foo($n) where $n < 1.5 }").is_err());
+    assert!(parse("macro m($n: lit) { foo($n) where $n in 0..\"a\" }").is_err());
+    assert!(parse("macro m($x: ident) { foo($x) where $x used after > 1.0 }").is_err());
+    let overflow = "macro m($x: ident) { foo($x) where $x used after > 18446744073709551615 }";
+    assert!(parse(overflow).is_err());
+}
+
+#[test]
+fn negative_literals() {
+    assert!(parse("macro m($n: lit) { foo($n) where $n == -1 }").is_err());
+    assert!(parse("macro m($n: lit) { foo($n) where $n in -1..=1 }").is_err());
+    // `-1` is a negation of the literal `1`
+    let pattern = "macro m($n: lit) { foo($n) where $n == 1 }";
+    assert_eq!(count(pattern, "fn f() { foo(-1); foo(1); }"), 1);
+    assert_eq!(count("macro m($n: lit) { foo(-$n) where $n == 1 }", "fn f() { foo(-1); foo(1); }"), 1);
+}
+
+#[test]
+fn name_regex() {
+    let input = "fn f() { let tmp_a = 1; let b = 2; }";
+    assert_eq!(count("macro m($x: ident ~ \"^tmp\") { let $x = $_; }", input), 1);
+    assert_eq!(count("macro m() { let $x: ident ~ \"^tmp\" = $_; }", input), 1);
+}
+
+#[test]
+fn name_regex_on_other_kinds() {
+    assert!(parse("macro m($x: expr ~ \"a\") { foo($x) }").is_err());
+    assert!(parse("macro m() { foo($x: expr ~ \"a\") }").is_err());
+    assert!(parse("macro m($x: ident ~ a) { foo($x) }").is_err());
+    assert!(parse("macro m() { foo($_: ident ~ \"a\") }").is_err());
+    assert!(parse("macro m($x: expr) { foo($x) where $x ~ \"a\" }").is_err());
+}
+
+#[test]
+fn inline_metavars() {
+    let input = "fn f() { let a = b + 1; let c: u8 = 2; }";
+    assert_eq!(count("macro m() { let $x = $y: expr + 1; }", input), 1);
+    assert_eq!(count("macro m() { let $x: $t = $e; }", input), 1);
+}
+
+#[test]
+fn inferred_binding_and_use() {
+    let input = "fn f() { let a = b; drop(a); let c = d; drop(e); }";
+    assert_eq!(count("macro m() { let $t = $x; drop($t); }", input), 1);
+    assert_eq!(count("macro m() { let $t = $x; drop($x); }", input), 0);
+    let negated = "macro m() { let $t = $x; where not after { $t } }";
+    assert_eq!(count(negated, "fn f() { let a = b; foo(a); let c = d; }"), 1);
+}
+
+#[test]
+fn unused_declared_metavar() {
+    assert!(parse("macro m($x: expr, $y: expr) { $y + 1 }").is_err());
+    assert!(parse("macro m($x: expr, $y: expr) { $y + 1 where not after { $x } }").is_ok());
+}
+
+#[test]
+fn pat_metavar() {
+    let input = "fn f() { if let Some(a) = x.ok() {} if let Some(a) = x {} let (b, c) = y; }";
+    assert_eq!(count("macro m($p: pat, $x: expr) { if let Some($p) = $x.ok() {} }", input), 1);
+    assert_eq!(count("macro m($p: pat, $e: expr) { let $p = $e; }", input), 1);
+    assert!(parse("macro m($p: pat) { foo($p) }").is_err());
+}
+
+#[test]
+fn lit_metavar() {
+    let input = "fn f() { x.pow(2); x.pow(n); x.pow(\"a\"); }";
+    assert_eq!(count("macro m($x: expr, $l: lit) { $x.pow($l) }", input), 2);
+    assert_eq!(count("macro m($x: expr, $l: lit) { $x.pow($l) }", "fn f() { x.pow(a + 1); }"), 0);
+}
+
+#[test]
+fn block_metavar() {
+    let input = "fn f() { if c { a(); } else { a(); } if c { a(); } else { b(); } loop { x(); } }";
+    assert_eq!(count("macro m($c: expr, $b: block) { if $c $b else $b }", input), 1);
+    assert_eq!(count("macro m($b: block) { loop $b }", input), 1);
+    assert!(parse("macro m($b: block) { let $b = 1; }").is_err());
+}
+
+#[test]
+fn lifetime_metavar() {
+    let input = "fn f() { let x: &'a mut u8 = y; 'outer: loop {} let z: &u8 = w; }";
+    assert_eq!(count("macro m($a: lifetime, $t: ty, $e: expr) { let $x: &$a mut $t = $e; }", input), 1);
+    assert_eq!(count("macro m($a: lifetime) { $a: loop {} }", input), 1);
+    assert!(parse("macro m($a: lifetime) { foo($a) }").is_err());
+}
+
+#[test]
+fn lifetime_backreference() {
+    let input = "fn f() { let x: (&'a u8, &'a u8) = y; let z: (&'a u8, &'b u8) = w; }";
+    assert_eq!(count("macro m($a: lifetime) { let $x: (&$a u8, &$a u8) = $e; }", input), 1);
+}
+
+#[test]
+fn wildcard() {
+    let input = "fn f() { foo(a, b); foo(a, a); foo(a); }";
+    assert_eq!(count("macro m() { foo($_, $_) }", input), 2);
+    assert_eq!(count("macro m() { foo($_: expr) }", input), 1);
+    let found = matches("macro m() { foo($_, $_) }", input);
+    assert_eq!(found[0].bindings, "[]");
+}
+
+#[test]
+fn repetition() {
+    let input = "fn f() { foo(); foo(a); foo(a, b); }";
+    assert_eq!(count("macro m() { foo($($args: expr),*) }", input), 3);
+    assert_eq!(count("macro m() { foo($($args: expr),+) }", input), 2);
+    assert_eq!(count("macro m() { foo($($args: expr)?) }", input), 2);
+    let input = "fn f() { let t = x; a(); b(); drop(t); let u = y; drop(v); }";
+    let run = "macro m($t: ident, $x: expr) { let $t = $x; $($s: stmt;)* drop($t); }";
+    assert_eq!(count(run, input), 1);
+}
+
+#[test]
+fn optional_metavar() {
+    let input = "fn f() { let a: u8 = 1; let b = 2; let (c, d) = e; }";
+    assert_eq!(count("macro m($t: ident, $ty: ty?, $x: expr) { let $t: $ty = $x; }", input), 2);
+    assert_eq!(count("macro m($t: ident, $ty: ty, $x: expr) { let $t: $ty = $x; }", input), 1);
+}
+
+#[test]
+fn alternation() {
+    let input = "fn f() { x + 1 <= y; 1 + x <= y; x + 2 <= y; }";
+    assert_eq!(count("macro m($x: expr, $y: expr) { $($x + 1 | 1 + $x) <= $y }", input), 2);
+    assert_eq!(count("macro m($x: expr, $y: expr) { $($x + 3 | 3 + $x) <= $y }", input), 0);}
+
+#[test]
+fn alternation_with_logical_or() {
+    let input = "fn f() { a || b; b || a; a && b; a | b; }";
+    assert_eq!(count("macro m($a: expr, $b: expr) { $($a || $b | $b || $a) }", input), 2);
+    assert_eq!(count("macro m($a: expr, $b: expr) { $($a || $b | $b && $a) }", input), 3);
+    assert_eq!(count("macro m($a: expr, $b: expr) { $($a || $b | $b || $a) }", "fn f() { a | b; }"), 0);
+}
+
+#[test]
+fn metavar_constraints() {
+    let input = "fn f() { a = a; a = b; }";
+    assert_eq!(count("macro m($a: expr, $b: expr) { $a = $b; where $a != $b }", input), 1);
+    assert_eq!(count("macro m($a: expr, $b: expr) { $a = $b; where $a == $b }", input), 1);
+}
+
+#[test]
+fn literal_values() {
+    let input = "fn f() { x * 1.0; x * 2.5; y == true; s.starts_with(\"tmp_a\"); s.starts_with(\"b\"); }";
+    assert_eq!(count("macro m($x: expr, $f: lit) { $x * $f where $f == 1.0 }", input), 1);
+    assert_eq!(count("macro m($x: expr, $b: lit) { $x == $b where $b == false }", input), 0);
+    assert_eq!(count("macro m($x: expr, $b: lit) { $x == $b where $b != false }", input), 1);
+    assert_eq!(count("macro m($s: expr, $l: lit) { $s.starts_with($l) where $l ~ \"^tmp\" }", input), 1);
+}
+
+#[test]
+fn statement_gap() {
+    let input = "fn f() { let t = x; a(); b(); drop(t); let u = y; drop(v); }";
+    let found = matches("macro m($t: ident, $x: expr) { let $t = $x; ... drop($t); }", input);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].anchors, vec![0, 3]);
+    assert_eq!(count("macro m($t: ident, $x: expr) { let $t = $x; ... bar($t); }", input), 0);
+}
+
+#[test]
+fn statement_set() {
+    let input = "fn f() { let b = 2; x(); let a = 1; }";
+    let found = matches("macro m() { ${ let a = 1; let b = 2; } }", input);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].anchors, vec![0, 2]);
+    assert_eq!(count("macro m() { ${ let a = 1; let c = 3; } }", input), 0);
+}
+
+#[test]
+fn statement_set_backreference() {
+    let input = "fn f() { let b = x; let a = x; let c = y; }";
+    assert_eq!(count("macro m($e: expr) { ${ let a = $e; let b = $e; } }", input), 1);
+    assert_eq!(count("macro m($e: expr) { ${ let a = $e; let c = $e; } }", input), 0);
+}
+
+#[test]
+fn anchored_statements() {
+    let input = "fn f() -> u8 { let _guard = l.lock(); a(); return x; }";
+    assert_eq!(count("macro m($x: expr) { return $x; $ }", input), 1);
+    assert_eq!(count("macro m() { a(); $ }", input), 0);
+    assert_eq!(count("macro m($e: expr) { ^ let _guard = $e; }", input), 1);
+    assert_eq!(count("macro m() { ^ a(); }", input), 0);
+}
+
+#[test]
+fn tail_expression() {
+    let input = "fn f() -> u8 { a(); x }";
+    assert_eq!(count("macro m($x: expr) { $x $ }", input), 1);
+    assert_eq!(count("macro m() { a(); $ }", input), 0);
+}
+
+#[test]
+fn contexts() {
+    let input = "unsafe fn f() { transmute(a); }
+        fn g() { transmute(b); unsafe { transmute(c); } let h = || x.clone(); }
+        impl Drop for Foo { fn drop(&mut self) { x.clone(); } }
+        #[test] fn t() { x.clone(); }";
+    assert_eq!(count("macro m($x: expr) { transmute($x) where not inside unsafe }", input), 1);
+    assert_eq!(count("macro m($x: expr) { transmute($x) where inside unsafe }", input), 2);
+    assert_eq!(count("macro m($x: expr) { $x.clone() where inside closure }", input), 1);
+    assert_eq!(count("macro m($x: expr) { $x.clone() where inside impl Drop for $t }", input), 1);
+    assert_eq!(count("macro m($x: expr) { $x.clone() where inside impl Clone }", input), 0);
+    assert_eq!(count("macro m($x: expr) { $x.clone() where inside #[test] fn }", input), 1);
+    assert_eq!(count("macro m($x: expr) { $x.clone() where inside impl Fn() -> u8 }", input), 0);
+    // the return type isn't the trait's name
+    assert_eq!(count("macro m($x: expr) { $x.clone() where inside impl Fn() -> Drop }", input), 0);
+    assert!(parse("macro m($x: expr) { $x.clone() where inside impl Drop> }").is_err());
+}
+
+#[test]
+fn use_counts() {
+    let input = "fn f() { let a = 1; foo(a); let b = 2; foo(b); bar(b); }";
+    let once = "macro m($t: ident, $x: expr) { let $t = $x; where $t used after == 1 }";
+    let found = matches(once, input);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].uses, vec![1]);
+    let twice = "macro m($t: ident, $x: expr) { let $t = $x; where $t used after >= 2 }";
+    assert_eq!(matches(twice, input)[0].uses, vec![2]);
+    assert_eq!(count("macro m($t: ident, $x: expr) { let $t = $x; where $t used after > 2 }", input), 0);
+}
+
+#[test]
+fn malformed_groups() {
+    assert!(parse("macro m($a: expr) { foo($($a),*) }").is_ok());
+    assert!(parse("macro m($a: expr) { foo($($a)) }").is_err());
+    assert!(parse("macro m($a: expr) { foo($($a),) }").is_err());
+    assert!(parse("macro m($a: expr) { foo($ 1) }").is_err());
+    assert!(parse("macro m($e: expr) { foo($e) where not within { Some($( $( _ ),* )*) } }").is_err());
+}
+
+#[test]
+fn wildcard_annotations() {
+    assert!(parse("macro m() { foo($_: expr) }").is_ok());
+    assert!(parse("macro m() { foo($_: expr?) }").is_err());
+    assert!(parse("macro m() { foo($_ ~ \"re\") }").is_err());
+    assert!(parse("macro m() { let $_: ident ~ \"re\" = 1; }").is_err());
+}
+
+#[test]
+fn use_counts_within_block() {
+    let input = "fn f() { { let a = 1; foo(a); } { let a = 2; bar(a); baz(a); } foo(a); }";
+    let once = "macro m($t: ident, $x: expr) { let $t = $x; where $t used after == 1 }";
+    assert_eq!(count(once, input), 1);
+    let twice = "macro m($t: ident, $x: expr) { let $t = $x; where $t used after == 2 }";
+    assert_eq!(count(twice, input), 1);
+    assert_eq!(count("macro m($t: ident) { let $t = 1; where not after { bar($t) } }", input), 1);
+}
+
+#[test]
+fn too_many_metavars() {
+    let body = |n: usize| (1..=n).map(|i| format!("foo($x{});", i)).collect::<String>();
+    assert!(parse(&format!("macro m() {{ {} }}", body(200))).is_ok());
+    assert!(parse(&format!("macro m() {{ {} }}", body(300))).is_err());
+}